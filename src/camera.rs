@@ -0,0 +1,196 @@
+use super::parse_named;
+
+/// Maps the offset of a point from the center of the view to its offset in the image, before the camera transform
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Projection {
+    #[default]
+    Linear,
+    /// `(ln(r / radius), θ)`: logarithmic spirals around the center become straight lines, and similar copies tile the image
+    LogPolar {
+        radius: f64,
+    },
+    /// `(θ, ln(r / radius))`: the polar image unwrapped, with the angle along x and the log-radius along y
+    Polar {
+        radius: f64,
+    },
+    /// Keeps the area within `radius` of the center mostly undistorted, and squeezes the whole plane into the disk of that radius
+    Fisheye {
+        radius: f64,
+    },
+    /// Inversion in the circle of radius `radius`: points close to the center are sent far away and vice versa
+    Inversion {
+        radius: f64,
+    },
+}
+
+impl Projection {
+    /// Creates a projection from its name and its optional radius
+    pub fn new(name: &str, params: &[f64]) -> Result<Self, String> {
+        let radius = match params {
+            [] => 1.0,
+            [radius] if *radius > 0.0 => *radius,
+            [radius] => return Err(format!("Expected the radius of the projection to be positive, got {}", radius)),
+            _ => return Err(format!("Too many parameters for projection '{}'", name)),
+        };
+
+        match name {
+            "linear" if params.is_empty() => Ok(Self::Linear),
+            "linear" => Err(String::from("The linear projection has no parameter")),
+            "log-polar" => Ok(Self::LogPolar {radius}),
+            "polar" => Ok(Self::Polar {radius}),
+            "fisheye" => Ok(Self::Fisheye {radius}),
+            "inversion" => Ok(Self::Inversion {radius}),
+            _ => Err(format!("Unknown projection '{}', expected one of linear, log-polar, polar, fisheye or inversion", name)),
+        }
+    }
+
+    /// Projects an offset from the center of the view; the result isn't finite for the center itself with some projections
+    #[inline]
+    pub fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        match self {
+            Self::Linear => (x, y),
+            Self::LogPolar {radius} => ((x.hypot(y) / radius).ln(), y.atan2(x)),
+            Self::Polar {radius} => (y.atan2(x), (x.hypot(y) / radius).ln()),
+            Self::Fisheye {radius} => {
+                let r = x.hypot(y);
+                if r == 0.0 {
+                    (0.0, 0.0)
+                } else {
+                    let factor = radius * (r / radius).tanh() / r;
+                    (x * factor, y * factor)
+                }
+            }
+            Self::Inversion {radius} => {
+                let factor = radius * radius / (x * x + y * y);
+                (x * factor, y * factor)
+            }
+        }
+    }
+}
+
+/// Parses a projection in the `name[:radius]` format, for instance `log-polar:0.5`
+impl std::str::FromStr for Projection {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (name, params) = parse_named(raw)?;
+
+        Self::new(name, &params)
+    }
+}
+
+/// Transform applied to the points around the center of the view before they are drawn:
+/// the projection, followed by a linear transform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub projection: Projection,
+    /// Counterclockwise rotation of the image, in degrees
+    pub rotation: f64,
+    /// Factors by which the image is stretched along the horizontal and vertical axes of the frame, after the rotation
+    pub stretch: (f64, f64),
+    /// Mirrors the image horizontally
+    pub flip_x: bool,
+    /// Mirrors the image vertically
+    pub flip_y: bool,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            projection: Projection::Linear,
+            rotation: 0.0,
+            stretch: (1.0, 1.0),
+            flip_x: false,
+            flip_y: false,
+        }
+    }
+}
+
+impl Camera {
+    /// Creates a camera from a list of named settings: `rotation degrees`, `stretch x y`, `flip-x`, `flip-y`,
+    /// and the name of a projection with its optional radius; settings that aren't given keep their default value
+    pub fn new(settings: &[(String, Vec<f64>)]) -> Result<Self, String> {
+        let mut res = Self::default();
+
+        for (name, params) in settings {
+            match (name.as_str(), &params[..]) {
+                ("rotation", [degrees]) => res.rotation = *degrees,
+                ("stretch", [x, y]) => {
+                    if *x <= 0.0 || *y <= 0.0 {
+                        return Err(format!("Expected the stretch factors to be positive, got {} and {}", x, y));
+                    }
+                    res.stretch = (*x, *y);
+                }
+                ("flip-x", []) => res.flip_x = true,
+                ("flip-y", []) => res.flip_y = true,
+                ("rotation", _) => return Err(String::from("Expected rotation to have one parameter, in degrees")),
+                ("stretch", _) => return Err(String::from("Expected stretch to have two parameters, along x and y")),
+                ("flip-x" | "flip-y", _) => return Err(format!("Expected {} to have no parameter", name)),
+                ("linear" | "log-polar" | "polar" | "fisheye" | "inversion", _) => res.projection = Projection::new(name, params)?,
+                _ => return Err(format!("Unknown camera setting '{}', expected one of rotation, stretch, flip-x, flip-y or a projection", name)),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Returns the matrix `[a, b, c, d]` mapping a projected offset `(dx, dy)` from the center of the view
+    /// to the offset `(a dx + b dy, c dx + d dy)` in the frame, whose y axis points down
+    pub fn matrix(&self) -> [f64; 4] {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let sx = if self.flip_x { -self.stretch.0 } else { self.stretch.0 };
+        let sy = if self.flip_y { -self.stretch.1 } else { self.stretch.1 };
+
+        [sx * cos, sx * sin, -sy * sin, sy * cos]
+    }
+
+    /// Maps an offset in the frame back to a projected offset from the center of the view
+    pub fn inverse(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let [a, b, c, d] = self.matrix();
+        let det = a * d - b * c;
+
+        ((d * x - b * y) / det, (a * y - c * x) / det)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_camera() {
+        assert_eq!(Camera::default().matrix(), [1.0, 0.0, 0.0, 1.0]);
+
+        let settings = [
+            (String::from("rotation"), vec![90.0]),
+            (String::from("stretch"), vec![2.0, 1.0]),
+            (String::from("flip-y"), vec![]),
+        ];
+        let camera = Camera::new(&settings).unwrap();
+        let [a, b, c, d] = camera.matrix();
+        // A point on the right of the center ends up above it, then gets flipped below it
+        let (x, y) = (a * 1.0 + b * 0.0, c * 1.0 + d * 0.0);
+        assert!(x.abs() < 1e-12 && (y - 1.0).abs() < 1e-12);
+
+        let (x, y) = camera.inverse((a * 0.3 + b * -0.7, c * 0.3 + d * -0.7));
+        assert!((x - 0.3).abs() < 1e-12 && (y + 0.7).abs() < 1e-12);
+
+        assert!(Camera::new(&[(String::from("stretch"), vec![0.0, 1.0])]).is_err());
+    }
+
+    #[test]
+    fn test_projection() {
+        // Multiplying by e rotated by 1 radian translates the log-polar image by (1, 1)
+        let log_polar = Projection::LogPolar {radius: 1.0};
+        let (x, y) = log_polar.apply((0.3, 0.2));
+        let (x2, y2) = log_polar.apply((std::f64::consts::E * (0.3 * 1f64.cos() - 0.2 * 1f64.sin()), std::f64::consts::E * (0.3 * 1f64.sin() + 0.2 * 1f64.cos())));
+        assert!((x2 - x - 1.0).abs() < 1e-12 && (y2 - y - 1.0).abs() < 1e-12);
+
+        assert_eq!(Projection::Inversion {radius: 2.0}.apply((4.0, 0.0)), (1.0, 0.0));
+        let (x, _) = Projection::Fisheye {radius: 1.0}.apply((1e6, 0.0));
+        assert!(x <= 1.0);
+
+        assert_eq!("polar:2".parse::<Projection>(), Ok(Projection::Polar {radius: 2.0}));
+        assert!("linear:2".parse::<Projection>().is_err());
+    }
+}
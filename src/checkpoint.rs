@@ -0,0 +1,293 @@
+//! Checkpoints of a render, from which it can be resumed or merged with other renders of the same scene.
+//!
+//! A checkpoint starts with the following header, with all values little-endian,
+//! and is followed by the accumulation buffer in the raw format of `export`:
+//!
+//! ```text
+//! magic        8 bytes  "CHAOSCKP"
+//! version      u32      currently 1
+//! script hash  u64      see `script_hash`
+//! has seed     u8       1 if the render was seeded, 0 otherwise
+//! seed         u64      0 if the render wasn't seeded
+//! scale        f64
+//! center       2 × f64
+//! rotation     f64      the camera
+//! stretch      2 × f64
+//! flips        u8       bit 0 for flip-x, bit 1 for flip-y
+//! projection   u8       0 to 4 for linear, log-polar, polar, fisheye and inversion
+//! radius       f64      the radius of the projection, 0 if linear
+//! wrap         u8       1 if the accumulation buffer wraps around its edges
+//! supersample  u32      the accumulation buffer is this many times larger than the image along each axis
+//! color space  u8       0 for linear sRGB, 1 for Oklab
+//! polygon      u32      the number of sides of the shape given by --polygon, 0 if the script sets SHAPE
+//! splat        u8       0 to 2 for nearest, bilinear and gaussian
+//! sigma        f64      the standard deviation of the gaussian splatting, 0 otherwise
+//! scatter      u64      the number of scatter steps
+//! burn-in      u64      the number of burn-in steps
+//! ```
+
+use super::camera::{Camera, Projection};
+use super::color::ColorSpace;
+use super::splat::Splat;
+use super::export::{self, invalid_data, read_u8, read_u32, read_u64};
+use super::world::State;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"CHAOSCKP";
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// Describes the render that a checkpoint belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheckpointMeta {
+    pub script_hash: u64,
+    pub seed: Option<u64>,
+    pub zoom: f64,
+    pub center: (f64, f64),
+    pub camera: Camera,
+    pub wrap: bool,
+    pub supersample: usize,
+    pub color_space: ColorSpace,
+    /// The number of sides of the shape given on the command line, which the script hash doesn't cover; unset if the script sets SHAPE
+    pub polygon: Option<usize>,
+    pub splat: Splat,
+    pub scatter_steps: usize,
+    pub burnin_steps: usize,
+}
+
+impl CheckpointMeta {
+    /// Returns an error describing why a render described by `self` can't accumulate into `other`
+    pub fn check_compatible(&self, other: &CheckpointMeta) -> Result<(), String> {
+        if self.script_hash != other.script_hash {
+            return Err(String::from("the script changed"));
+        }
+        if self.zoom != other.zoom || self.center != other.center {
+            return Err(format!(
+                "the view changed (scale {} and center {:?}, expected scale {} and center {:?})",
+                self.zoom, self.center, other.zoom, other.center
+            ));
+        }
+        if self.camera != other.camera {
+            return Err(format!("the camera changed ({:?}, expected {:?})", self.camera, other.camera));
+        }
+        if self.wrap != other.wrap {
+            return Err(String::from("the wrap-around mode changed"));
+        }
+        if self.supersample != other.supersample {
+            return Err(format!("the supersampling changed ({}, expected {})", self.supersample, other.supersample));
+        }
+        if self.color_space != other.color_space {
+            return Err(format!("the color space changed ({:?}, expected {:?})", self.color_space, other.color_space));
+        }
+        if self.polygon != other.polygon {
+            return Err(format!("the shape changed (polygon {:?}, expected {:?})", self.polygon, other.polygon));
+        }
+        if self.splat != other.splat {
+            return Err(format!("the splatting changed ({:?}, expected {:?})", self.splat, other.splat));
+        }
+        if self.scatter_steps != other.scatter_steps || self.burnin_steps != other.burnin_steps {
+            return Err(format!(
+                "the steps changed ({} scatter and {} burn-in steps, expected {} and {})",
+                self.scatter_steps, self.burnin_steps, other.scatter_steps, other.burnin_steps
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Hashes the source of a script with 64-bit FNV-1a, which doesn't depend on the platform or the version of Rust
+pub fn script_hash(script: &str) -> u64 {
+    script.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn write<W: Write>(meta: &CheckpointMeta, state: &State, writer: W) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+
+    writer.write_all(CHECKPOINT_MAGIC)?;
+    writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+    writer.write_all(&meta.script_hash.to_le_bytes())?;
+    writer.write_all(&[u8::from(meta.seed.is_some())])?;
+    writer.write_all(&meta.seed.unwrap_or(0).to_le_bytes())?;
+    writer.write_all(&meta.zoom.to_le_bytes())?;
+    writer.write_all(&meta.center.0.to_le_bytes())?;
+    writer.write_all(&meta.center.1.to_le_bytes())?;
+    writer.write_all(&meta.camera.rotation.to_le_bytes())?;
+    writer.write_all(&meta.camera.stretch.0.to_le_bytes())?;
+    writer.write_all(&meta.camera.stretch.1.to_le_bytes())?;
+    writer.write_all(&[u8::from(meta.camera.flip_x) | u8::from(meta.camera.flip_y) << 1])?;
+    let (projection, radius) = match meta.camera.projection {
+        Projection::Linear => (0, 0.0),
+        Projection::LogPolar {radius} => (1, radius),
+        Projection::Polar {radius} => (2, radius),
+        Projection::Fisheye {radius} => (3, radius),
+        Projection::Inversion {radius} => (4, radius),
+    };
+    writer.write_all(&[projection])?;
+    writer.write_all(&f64::to_le_bytes(radius))?;
+    writer.write_all(&[u8::from(meta.wrap)])?;
+    writer.write_all(&(meta.supersample as u32).to_le_bytes())?;
+    writer.write_all(&[match meta.color_space {
+        ColorSpace::Linear => 0,
+        ColorSpace::Oklab => 1,
+    }])?;
+    writer.write_all(&(meta.polygon.unwrap_or(0) as u32).to_le_bytes())?;
+    let (splat, sigma) = match meta.splat {
+        Splat::Nearest => (0, 0.0),
+        Splat::Bilinear => (1, 0.0),
+        Splat::Gaussian {sigma} => (2, sigma),
+    };
+    writer.write_all(&[splat])?;
+    writer.write_all(&f64::to_le_bytes(sigma))?;
+    writer.write_all(&(meta.scatter_steps as u64).to_le_bytes())?;
+    writer.write_all(&(meta.burnin_steps as u64).to_le_bytes())?;
+
+    export::write_raw(state, &mut writer)?;
+    writer.flush()
+}
+
+pub fn read<R: Read>(reader: R) -> io::Result<(CheckpointMeta, State)> {
+    let mut reader = io::BufReader::new(reader);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != CHECKPOINT_MAGIC {
+        return Err(invalid_data(String::from("Not a checkpoint")));
+    }
+
+    let version = read_u32(&mut reader)?;
+    if version != CHECKPOINT_VERSION {
+        return Err(invalid_data(format!("Unsupported checkpoint version {}, expected {}", version, CHECKPOINT_VERSION)));
+    }
+
+    let script_hash = read_u64(&mut reader)?;
+    let has_seed = read_u8(&mut reader)?;
+    let seed = read_u64(&mut reader)?;
+    let zoom = f64::from_bits(read_u64(&mut reader)?);
+    let center = (f64::from_bits(read_u64(&mut reader)?), f64::from_bits(read_u64(&mut reader)?));
+
+    let rotation = f64::from_bits(read_u64(&mut reader)?);
+    let stretch = (f64::from_bits(read_u64(&mut reader)?), f64::from_bits(read_u64(&mut reader)?));
+    let flips = read_u8(&mut reader)?;
+    let projection = read_u8(&mut reader)?;
+    let radius = f64::from_bits(read_u64(&mut reader)?);
+    let projection = match projection {
+        0 => Projection::Linear,
+        1 => Projection::LogPolar {radius},
+        2 => Projection::Polar {radius},
+        3 => Projection::Fisheye {radius},
+        4 => Projection::Inversion {radius},
+        x => return Err(invalid_data(format!("Unknown projection {}", x))),
+    };
+    let camera = Camera {
+        projection,
+        rotation,
+        stretch,
+        flip_x: flips & 1 != 0,
+        flip_y: flips & 2 != 0,
+    };
+
+    let wrap = read_u8(&mut reader)? != 0;
+    let supersample = read_u32(&mut reader)? as usize;
+    let color_space = match read_u8(&mut reader)? {
+        0 => ColorSpace::Linear,
+        1 => ColorSpace::Oklab,
+        x => return Err(invalid_data(format!("Unknown color space {}", x))),
+    };
+
+    let polygon = read_u32(&mut reader)? as usize;
+    let splat = read_u8(&mut reader)?;
+    let sigma = f64::from_bits(read_u64(&mut reader)?);
+    let splat = match splat {
+        0 => Splat::Nearest,
+        1 => Splat::Bilinear,
+        2 => Splat::Gaussian {sigma},
+        x => return Err(invalid_data(format!("Unknown splatting mode {}", x))),
+    };
+    let scatter_steps = read_u64(&mut reader)? as usize;
+    let burnin_steps = read_u64(&mut reader)? as usize;
+
+    let meta = CheckpointMeta {
+        script_hash,
+        seed: (has_seed != 0).then_some(seed),
+        zoom,
+        center,
+        camera,
+        wrap,
+        supersample,
+        color_space,
+        polygon: (polygon != 0).then_some(polygon),
+        splat,
+        scatter_steps,
+        burnin_steps,
+    };
+
+    Ok((meta, export::read_raw(reader)?))
+}
+
+/// Writes a checkpoint to `path`; the previous checkpoint is only replaced once the new one is complete
+pub fn save(path: &Path, meta: &CheckpointMeta, state: &State) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    write(meta, state, std::fs::File::create(&tmp_path)?)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+pub fn load(path: &Path) -> io::Result<(CheckpointMeta, State)> {
+    read(std::fs::File::open(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::world::Pixel;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let meta = CheckpointMeta {
+            script_hash: script_hash("(define SCALE 2.0)"),
+            seed: Some(42),
+            zoom: 2.0,
+            center: (0.5, -1.0),
+            camera: Camera {projection: Projection::LogPolar {radius: 0.5}, rotation: 30.0, flip_y: true, ..Camera::default()},
+            wrap: true,
+            supersample: 1,
+            color_space: ColorSpace::Oklab,
+            polygon: Some(5),
+            splat: Splat::Gaussian {sigma: 0.75},
+            scatter_steps: 7,
+            burnin_steps: 100,
+        };
+        let state = State::new(vec![Pixel {n: 3.0, ..Pixel::default()}; 4], 10, 2, 2);
+
+        let mut buffer = Vec::new();
+        write(&meta, &state, &mut buffer).unwrap();
+        let (read_meta, read_state) = read(&buffer[..]).unwrap();
+
+        assert_eq!(read_meta, meta);
+        assert_eq!((read_state.width, read_state.height, read_state.steps), (2, 2, 10));
+        assert_eq!(read_state.pixels[3].n, 3.0);
+
+        let other = CheckpointMeta {
+            script_hash: script_hash("(define SCALE 2.5)"),
+            ..meta
+        };
+        assert!(meta.check_compatible(&read_meta).is_ok());
+        assert!(other.check_compatible(&meta).is_err());
+
+        // Renders that accumulated differently can neither be resumed nor merged together
+        for other in [
+            CheckpointMeta {polygon: None, ..meta},
+            CheckpointMeta {splat: Splat::Bilinear, ..meta},
+            CheckpointMeta {scatter_steps: 3, ..meta},
+            CheckpointMeta {burnin_steps: 0, ..meta},
+        ] {
+            assert!(other.check_compatible(&meta).is_err(), "{:?}", other);
+        }
+        // The seed is the only setting expected to differ between merged renders
+        assert!(CheckpointMeta {seed: Some(43), ..meta}.check_compatible(&meta).is_ok());
+    }
+}
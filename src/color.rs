@@ -0,0 +1,129 @@
+use super::shape::Shape;
+
+/// Converts an sRGB-encoded component between 0 and 1 to linear light, using the exact piecewise sRGB transfer curve
+#[inline]
+pub fn srgb_to_linear(x: f64) -> f64 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Inverse of `srgb_to_linear`
+#[inline]
+pub fn linear_to_srgb(x: f64) -> f64 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Encodes a linear component as an 8-bit sRGB value, clamping it between 0 and 1
+#[inline]
+pub fn encode_srgb(x: f64) -> u8 {
+    (linear_to_srgb(x.clamp(0.0, 1.0)) * 255.0).round() as u8
+}
+
+/// The color space in which the colors of the points are blended by the rules and accumulated in the pixels.
+/// Shapes and backgrounds are always given in linear sRGB, and the accumulated colors are converted back to it before being drawn.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorSpace {
+    /// Linear sRGB, where blending two colors is physically accurate but may yield dull midpoints
+    #[default]
+    Linear,
+    /// Björn Ottosson's perceptual Oklab space, where blends keep their lightness and chroma
+    Oklab,
+}
+
+impl ColorSpace {
+    /// Converts a linear sRGB color to this color space
+    #[inline]
+    pub fn from_linear(&self, color: (f64, f64, f64)) -> (f64, f64, f64) {
+        match self {
+            Self::Linear => color,
+            Self::Oklab => linear_to_oklab(color),
+        }
+    }
+
+    /// Converts a color of this color space to linear sRGB
+    #[inline]
+    pub fn to_linear(&self, color: (f64, f64, f64)) -> (f64, f64, f64) {
+        match self {
+            Self::Linear => color,
+            Self::Oklab => oklab_to_linear(color),
+        }
+    }
+
+    /// Converts the colors of a shape, given in linear sRGB, to this color space
+    pub fn convert_shape(&self, shape: &Shape) -> Shape {
+        shape.iter().map(|point| {
+            let mut point = *point;
+            point.set_color(self.from_linear(point.color()));
+            point
+        }).collect()
+    }
+}
+
+fn linear_to_oklab((r, g, b): (f64, f64, f64)) -> (f64, f64, f64) {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    (
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    )
+}
+
+fn oklab_to_linear((l, a, b): (f64, f64, f64)) -> (f64, f64, f64) {
+    let l_ = l + 0.3963377774 * a + 0.2158037573 * b;
+    let m_ = l - 0.1055613458 * a - 0.0638541728 * b;
+    let s_ = l - 0.0894841775 * a - 1.2914855480 * b;
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+
+    (
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    )
+}
+
+/// Parses either `linear` or `oklab`
+impl std::str::FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw {
+            "linear" => Ok(Self::Linear),
+            "oklab" => Ok(Self::Oklab),
+            _ => Err(format!("Unknown color space '{}', expected either linear or oklab", raw)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_color_space() {
+        for x in [0.0, 0.002, 0.04, 0.2, 0.5, 1.0] {
+            assert!((srgb_to_linear(linear_to_srgb(x)) - x).abs() < 1e-12);
+        }
+        assert_eq!(encode_srgb(1.0), 255);
+        assert_eq!(encode_srgb(srgb_to_linear(128.0 / 255.0)), 128);
+
+        let color = (0.8, 0.1, 0.3);
+        let (r, g, b) = ColorSpace::Oklab.to_linear(ColorSpace::Oklab.from_linear(color));
+        assert!((r - color.0).abs() < 1e-6 && (g - color.1).abs() < 1e-6 && (b - color.2).abs() < 1e-6);
+
+        // White has a lightness of 1 and no chroma
+        let (l, a, b) = ColorSpace::Oklab.from_linear((1.0, 1.0, 1.0));
+        assert!((l - 1.0).abs() < 1e-6 && a.abs() < 1e-6 && b.abs() < 1e-6);
+
+        assert_eq!("oklab".parse::<ColorSpace>(), Ok(ColorSpace::Oklab));
+    }
+}
@@ -0,0 +1,183 @@
+//! Exports the accumulation buffer of a render before it is tone mapped, so that it can be graded in other tools.
+//!
+//! Two formats are supported: OpenEXR, with one 32-bit float channel per field of `Pixel`,
+//! and a raw format that keeps the full 64-bit precision of the sums. All values are little-endian:
+//!
+//! ```text
+//! magic     8 bytes  "CHAOSACC"
+//! version   u32      currently 1
+//! width     u32
+//! height    u32
+//! steps     u64      number of steps accumulated in the buffer
+//! channels  u32      number of channels, followed for each channel by its name length (u8) and its ASCII name
+//! data      f64      width * height * channels values; rows from top to bottom, pixels from left to right,
+//!                    with the channels of a pixel next to each other
+//! ```
+//!
+//! The channels are `R`, `G` and `B` (the sums of the linear color of the points that landed on the pixel, times their weight),
+//! `N` (the sum of their weights), and with the `sigma` feature `L` and `L2` (the sums of their lightness and squared lightness).
+//! Dividing `R`, `G` and `B` by `N` yields the average color of the pixel.
+
+use super::world::{Pixel, State};
+use std::io::{self, Read, Seek, Write};
+
+pub const RAW_MAGIC: &[u8; 8] = b"CHAOSACC";
+pub const RAW_VERSION: u32 = 1;
+
+type Channel = (&'static str, fn(&Pixel) -> f64, fn(&mut Pixel, f64));
+
+/// The exported fields of `Pixel`, with their name, getter and setter
+const CHANNELS: &[Channel] = &[
+    ("R", |p| p.r_sum, |p, x| p.r_sum = x),
+    ("G", |p| p.g_sum, |p, x| p.g_sum = x),
+    ("B", |p| p.b_sum, |p, x| p.b_sum = x),
+    ("N", |p| p.n, |p, x| p.n = x),
+    #[cfg(feature = "sigma")]
+    ("L", |p| p.l_sum, |p, x| p.l_sum = x),
+    #[cfg(feature = "sigma")]
+    ("L2", |p| p.l_squared, |p, x| p.l_squared = x),
+];
+
+/// Writes `state` in the raw format described in the module documentation
+pub fn write_raw<W: Write>(state: &State, writer: W) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+
+    writer.write_all(RAW_MAGIC)?;
+    writer.write_all(&RAW_VERSION.to_le_bytes())?;
+    writer.write_all(&(state.width as u32).to_le_bytes())?;
+    writer.write_all(&(state.height as u32).to_le_bytes())?;
+    writer.write_all(&(state.steps as u64).to_le_bytes())?;
+
+    writer.write_all(&(CHANNELS.len() as u32).to_le_bytes())?;
+    for (name, _, _) in CHANNELS {
+        writer.write_all(&[name.len() as u8])?;
+        writer.write_all(name.as_bytes())?;
+    }
+
+    for pixel in state.pixels.iter() {
+        for (_, get, _) in CHANNELS {
+            writer.write_all(&get(pixel).to_le_bytes())?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Reads a state written by `write_raw`; fails if its channels don't match the ones of this build
+pub fn read_raw<R: Read>(reader: R) -> io::Result<State> {
+    let mut reader = io::BufReader::new(reader);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != RAW_MAGIC {
+        return Err(invalid_data(String::from("Not a raw accumulation buffer")));
+    }
+
+    let version = read_u32(&mut reader)?;
+    if version != RAW_VERSION {
+        return Err(invalid_data(format!("Unsupported version {}, expected {}", version, RAW_VERSION)));
+    }
+
+    let width = read_u32(&mut reader)? as usize;
+    let height = read_u32(&mut reader)? as usize;
+    let steps = read_u64(&mut reader)? as usize;
+
+    let n_channels = read_u32(&mut reader)? as usize;
+    let mut names = Vec::with_capacity(n_channels);
+    for _ in 0..n_channels {
+        let mut length = [0u8];
+        reader.read_exact(&mut length)?;
+        let mut name = vec![0u8; length[0] as usize];
+        reader.read_exact(&mut name)?;
+        names.push(String::from_utf8_lossy(&name).into_owned());
+    }
+
+    let expected = CHANNELS.iter().map(|(name, _, _)| *name).collect::<Vec<_>>();
+    if names != expected {
+        return Err(invalid_data(format!("Expected the channels {:?}, got {:?}", expected, names)));
+    }
+
+    let mut pixels = vec![Pixel::default(); width * height];
+    let mut buffer = [0u8; 8];
+    for pixel in pixels.iter_mut() {
+        for (_, _, set) in CHANNELS {
+            reader.read_exact(&mut buffer)?;
+            set(pixel, f64::from_le_bytes(buffer));
+        }
+    }
+
+    Ok(State::new(pixels, steps, width, height))
+}
+
+/// Writes `state` as an OpenEXR image, with one layer named `accumulation` containing the channels
+/// described in the module documentation; the sums are rounded to 32-bit floats
+pub fn write_exr<W: Write + Seek>(state: &State, writer: W) -> io::Result<()> {
+    use exr::prelude::*;
+
+    let channels = CHANNELS.iter().map(|(name, get, _)| {
+        let samples = state.pixels.iter().map(|p| get(p) as f32).collect::<Vec<_>>();
+        AnyChannel::new(*name, FlatSamples::F32(samples))
+    }).collect::<SmallVec<_>>();
+
+    let layer = Layer::new(
+        (state.width, state.height),
+        LayerAttributes::named("accumulation"),
+        Encoding::default(),
+        AnyChannels::sort(channels),
+    );
+
+    Image::from_layer(layer)
+        .write()
+        .to_buffered(io::BufWriter::new(writer))
+        .map_err(io::Error::other)
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buffer = [0u8; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+pub(crate) fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raw_roundtrip() {
+        let pixels = (0..6).map(|i| Pixel {
+            r_sum: i as f64 * 0.5,
+            b_sum: 1.0 / (i + 1) as f64,
+            n: i as f64,
+            ..Pixel::default()
+        }).collect::<Vec<_>>();
+        let state = State::new(pixels, 1234, 3, 2);
+
+        let mut buffer = Vec::new();
+        write_raw(&state, &mut buffer).unwrap();
+        let read = read_raw(&buffer[..]).unwrap();
+
+        assert_eq!((read.width, read.height, read.steps), (3, 2, 1234));
+        for (a, b) in state.pixels.iter().zip(read.pixels.iter()) {
+            assert_eq!((a.r_sum, a.g_sum, a.b_sum, a.n), (b.r_sum, b.g_sum, b.b_sum, b.n));
+        }
+
+        buffer[0] = b'X';
+        assert!(read_raw(&buffer[..]).is_err());
+    }
+}
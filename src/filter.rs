@@ -0,0 +1,353 @@
+use super::world::{Pixel, State};
+
+/// Reconstruction filter used to downsample a supersampled accumulation buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Filter {
+    /// Sums the `factor × factor` pixels covering each pixel of the image
+    #[default]
+    Box,
+    /// Lanczos filter with 3 lobes; sharper, but may ring around bright filaments
+    Lanczos,
+    /// Mitchell-Netravali filter with `B = C = 1/3`
+    Mitchell,
+}
+
+/// Renders at `factor` times the resolution of the image, which is then downsampled with `filter`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Supersample {
+    pub factor: usize,
+    pub filter: Filter,
+}
+
+impl Default for Supersample {
+    fn default() -> Self {
+        Self {
+            factor: 1,
+            filter: Filter::Box,
+        }
+    }
+}
+
+/// flam3-style adaptive density estimation, which blurs each pixel with a kernel whose radius shrinks as its density grows:
+/// sparse areas get smoothed out while dense areas stay sharp
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DensityEstimation {
+    pub max_radius: f64,
+    pub min_radius: f64,
+    /// The radius of a pixel of density `n` is `max(min_radius, max_radius / n^curve)`
+    pub curve: f64,
+}
+
+impl Default for DensityEstimation {
+    fn default() -> Self {
+        Self {
+            max_radius: 9.0,
+            min_radius: 0.0,
+            curve: 0.4,
+        }
+    }
+}
+
+impl Filter {
+    /// Half of the width of the filter, in pixels of the downsampled image
+    fn radius(&self) -> f64 {
+        match self {
+            Self::Box => 0.5,
+            Self::Lanczos => 3.0,
+            Self::Mitchell => 2.0,
+        }
+    }
+
+    fn weight(&self, x: f64) -> f64 {
+        let x = x.abs();
+
+        match self {
+            Self::Box => if x < 0.5 { 1.0 } else { 0.0 },
+            Self::Lanczos => {
+                if x < 1e-9 {
+                    1.0
+                } else if x < 3.0 {
+                    let px = std::f64::consts::PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                } else {
+                    0.0
+                }
+            }
+            Self::Mitchell => {
+                const B: f64 = 1.0 / 3.0;
+                const C: f64 = 1.0 / 3.0;
+
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x * x * x + (-18.0 + 12.0 * B + 6.0 * C) * x * x + (6.0 - 2.0 * B)) / 6.0
+                } else if x < 2.0 {
+                    ((-B - 6.0 * C) * x * x * x + (6.0 * B + 30.0 * C) * x * x + (-12.0 * B - 48.0 * C) * x + (8.0 * B + 24.0 * C)) / 6.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// For each of the `size` downsampled pixels along an axis, returns the source pixels contributing to it and their weight.
+    /// The weights of each pixel sum up to `factor`, so that the total density is preserved.
+    /// If `wrap` is set, the taps falling outside of the axis wrap around to its other end.
+    fn taps(&self, size: usize, factor: usize, wrap: bool) -> Vec<Vec<(usize, f64)>> {
+        let source_size = (size * factor) as isize;
+        let radius = self.radius();
+
+        (0..size).map(|target| {
+            let center = target as f64 + 0.5;
+            let mut from = ((center - radius) * factor as f64).floor() as isize;
+            let mut to = ((center + radius) * factor as f64).ceil() as isize;
+            if !wrap {
+                from = from.max(0);
+                to = to.min(source_size);
+            }
+
+            let mut taps = (from..to)
+                .map(|source| (source.rem_euclid(source_size) as usize, self.weight((source as f64 + 0.5) / factor as f64 - center)))
+                .filter(|(_, weight)| *weight != 0.0)
+                .collect::<Vec<_>>();
+
+            let total = taps.iter().map(|(_, weight)| weight).sum::<f64>();
+            for (_, weight) in taps.iter_mut() {
+                *weight *= factor as f64 / total;
+            }
+
+            taps
+        }).collect()
+    }
+}
+
+impl Supersample {
+    /// Downsamples a state accumulated at `factor` times the resolution of the image.
+    /// The filter is applied to the sums of each pixel, so colors are averaged in linear light;
+    /// if `wrap` is set, the state is treated as a torus, like with `WorldParams::wrap`.
+    pub fn downsample(&self, state: &State, wrap: bool) -> State {
+        if self.factor <= 1 {
+            return state.clone();
+        }
+
+        let width = state.width / self.factor;
+        let height = state.height / self.factor;
+        let taps_x = self.filter.taps(width, self.factor, wrap);
+        let taps_y = self.filter.taps(height, self.factor, wrap);
+
+        // Horizontal pass, yielding a `width × state.height` buffer
+        let mut horizontal = vec![Pixel::default(); width * state.height];
+        for y in 0..state.height {
+            let row = &state.pixels[y * state.width..(y + 1) * state.width];
+            for (x, taps) in taps_x.iter().enumerate() {
+                let pixel = &mut horizontal[x + y * width];
+                for (source, weight) in taps {
+                    pixel.add_scaled(&row[*source], *weight);
+                }
+            }
+        }
+
+        // Vertical pass
+        let mut pixels = vec![Pixel::default(); width * height];
+        for (y, taps) in taps_y.iter().enumerate() {
+            for (source, weight) in taps {
+                let row = &horizontal[source * width..(source + 1) * width];
+                for (pixel, from) in pixels[y * width..(y + 1) * width].iter_mut().zip(row) {
+                    pixel.add_scaled(from, *weight);
+                }
+            }
+        }
+
+        // The negative lobes of Lanczos and Mitchell may yield negative sums
+        for pixel in pixels.iter_mut() {
+            pixel.clamp_negative();
+        }
+
+        State::new(pixels, state.steps, width, height)
+    }
+}
+
+impl DensityEstimation {
+    pub const MAX_RADIUS: f64 = 32.0;
+    /// Kernel radii are rounded to this fraction of a pixel
+    const RESOLUTION: f64 = 4.0;
+
+    pub fn new(max_radius: f64, min_radius: f64, curve: f64) -> Result<Self, String> {
+        if !(0.0..=Self::MAX_RADIUS).contains(&max_radius) {
+            return Err(format!("Expected the maximum radius to be between 0 and {}, got {}", Self::MAX_RADIUS, max_radius));
+        }
+        if !(0.0..=max_radius).contains(&min_radius) {
+            return Err(format!("Expected the minimum radius to be between 0 and the maximum radius, got {}", min_radius));
+        }
+        if curve <= 0.0 {
+            return Err(format!("Expected the curve to be positive, got {}", curve));
+        }
+
+        Ok(Self {max_radius, min_radius, curve})
+    }
+
+    pub fn radius(&self, n: f64) -> f64 {
+        (self.max_radius / n.powf(self.curve)).clamp(self.min_radius, self.max_radius)
+    }
+
+    /// Returns the offsets and weights of the kernel of index `index`, whose radius is `index / RESOLUTION`;
+    /// the weights follow `exp(-2 (d / radius)²)` like in flam3, and sum up to 1
+    fn kernel(index: usize) -> Vec<(isize, isize, f64)> {
+        let radius = index as f64 / Self::RESOLUTION;
+        let bound = radius.floor() as isize;
+
+        let mut kernel = Vec::new();
+        for dy in -bound..=bound {
+            for dx in -bound..=bound {
+                let d = ((dx * dx + dy * dy) as f64).sqrt() / radius;
+                if d <= 1.0 {
+                    kernel.push((dx, dy, (-2.0 * d * d).exp()));
+                }
+            }
+        }
+
+        let total = kernel.iter().map(|(_, _, weight)| weight).sum::<f64>();
+        for (_, _, weight) in kernel.iter_mut() {
+            *weight /= total;
+        }
+
+        kernel
+    }
+
+    /// Blurs every lit pixel of `state` with the kernel matching its density; the parts of the kernels outside of the frame are dropped,
+    /// unless `wrap` is set, in which case they wrap around to the opposite side of the frame
+    pub fn apply(&self, state: &State, wrap: bool) -> State {
+        let max_index = (self.max_radius * Self::RESOLUTION).round() as usize;
+        let mut kernels = vec![None; max_index + 1];
+        let mut pixels = vec![Pixel::default(); state.pixels.len()];
+
+        for (index, pixel) in state.pixels.iter().enumerate() {
+            if pixel.n <= 0.0 {
+                continue;
+            }
+
+            let kernel_index = (self.radius(pixel.n) * Self::RESOLUTION).round() as usize;
+            // Kernels smaller than a pixel leave it untouched
+            if kernel_index < Self::RESOLUTION as usize {
+                pixels[index].add_pixel(*pixel);
+                continue;
+            }
+
+            let kernel = kernels[kernel_index].get_or_insert_with(|| Self::kernel(kernel_index));
+            let (x, y) = ((index % state.width) as isize, (index / state.width) as isize);
+
+            for (dx, dy, weight) in kernel.iter() {
+                let (mut tx, mut ty) = (x + dx, y + dy);
+                if wrap {
+                    tx = tx.rem_euclid(state.width as isize);
+                    ty = ty.rem_euclid(state.height as isize);
+                }
+                if tx >= 0 && ty >= 0 && (tx as usize) < state.width && (ty as usize) < state.height {
+                    pixels[tx as usize + ty as usize * state.width].add_scaled(pixel, *weight);
+                }
+            }
+        }
+
+        State::new(pixels, state.steps, state.width, state.height)
+    }
+}
+
+/// Parses density estimation parameters in the `max_radius[:min_radius[:curve]]` format, for instance `9:0:0.4`
+impl std::str::FromStr for DensityEstimation {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let params = raw
+            .split(':')
+            .map(|x| x.parse::<f64>().map_err(|e| format!("{:?}", e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let default = Self::default();
+
+        match params[..] {
+            [max_radius] => Self::new(max_radius, default.min_radius, default.curve),
+            [max_radius, min_radius] => Self::new(max_radius, min_radius, default.curve),
+            [max_radius, min_radius, curve] => Self::new(max_radius, min_radius, curve),
+            _ => Err(String::from("Expected at most three parameters for density estimation")),
+        }
+    }
+}
+
+/// Parses either `box`, `lanczos` or `mitchell`
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw {
+            "box" => Ok(Self::Box),
+            "lanczos" => Ok(Self::Lanczos),
+            "mitchell" => Ok(Self::Mitchell),
+            _ => Err(format!("Unknown filter '{}', expected one of box, lanczos or mitchell", raw)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_downsample() {
+        let pixels = (0..36).map(|i| Pixel {
+            r_sum: (i % 6) as f64,
+            n: 1.0 + (i / 6) as f64,
+            ..Pixel::default()
+        }).collect::<Vec<_>>();
+        let state = State::new(pixels, 100, 6, 6);
+        let total = state.pixels.iter().map(|p| p.n).sum::<f64>();
+
+        let boxed = Supersample {factor: 2, filter: Filter::Box}.downsample(&state, false);
+        assert_eq!((boxed.width, boxed.height, boxed.steps), (3, 3, 100));
+        // The top-left pixel covers the pixels at (0, 0), (1, 0), (0, 1) and (1, 1)
+        assert_eq!(boxed.pixels[0].r_sum, 2.0);
+        assert_eq!(boxed.pixels[0].n, 6.0);
+
+        for filter in [Filter::Box, Filter::Lanczos, Filter::Mitchell] {
+            let downsampled = Supersample {factor: 3, filter}.downsample(&state, false);
+            let downsampled_total = downsampled.pixels.iter().map(|p| p.n).sum::<f64>();
+            assert!((downsampled_total - total).abs() < 1e-6 * total, "{:?}", filter);
+        }
+
+        // Wrapping around preserves the total density as well
+        let wrapped = Supersample {factor: 2, filter: Filter::Lanczos}.downsample(&state, true);
+        let wrapped_total = wrapped.pixels.iter().map(|p| p.n).sum::<f64>();
+        assert!((wrapped_total - total).abs() < 1e-6 * total);
+
+        // The a and b channels of Oklab are signed, and must survive the clamping of the negative lobes
+        let pixels = (0..16).map(|i| {
+            let mut pixel = Pixel {r_sum: 0.5, n: 1.0, ..Pixel::default()};
+            pixel.g_sum = -0.1 * (1 + i % 4) as f64;
+            pixel.b_sum = -0.2;
+            pixel
+        }).collect::<Vec<_>>();
+        let state = State::new(pixels, 16, 4, 4);
+        let boxed = Supersample {factor: 2, filter: Filter::Box}.downsample(&state, false);
+        assert!((boxed.pixels[0].g_sum + 0.6).abs() < 1e-9);
+        assert!((boxed.pixels[0].b_sum + 0.8).abs() < 1e-9);
+        let lanczos = Supersample {factor: 2, filter: Filter::Lanczos}.downsample(&state, true);
+        assert!(lanczos.pixels.iter().all(|p| p.g_sum < 0.0 && p.b_sum < 0.0));
+    }
+
+    #[test]
+    fn test_density_estimation() {
+        let mut pixels = vec![Pixel::default(); 81];
+        pixels[40] = Pixel {r_sum: 1.0, n: 1.0, ..Pixel::default()};
+        pixels[0] = Pixel {r_sum: 1000.0, n: 1000.0, ..Pixel::default()};
+        let state = State::new(pixels, 1001, 9, 9);
+
+        let de = DensityEstimation::new(3.0, 0.0, 0.5).unwrap();
+        let filtered = de.apply(&state, false);
+
+        // The sparse pixel in the center gets spread out, without losing any density
+        assert!(filtered.pixels[40].n < 1.0 && filtered.pixels[41].n > 0.0);
+        let center = filtered.pixels.iter().skip(1).map(|p| p.n).sum::<f64>();
+        assert!((center - 1.0).abs() < 1e-9);
+        // The radius of the dense pixel is below a pixel, so it is untouched
+        assert_eq!(filtered.pixels[0].n, 1000.0);
+
+        assert_eq!("4:1".parse::<DensityEstimation>(), Ok(DensityEstimation {max_radius: 4.0, min_radius: 1.0, curve: 0.4}));
+        assert!("4:5".parse::<DensityEstimation>().is_err());
+    }
+}
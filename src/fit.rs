@@ -0,0 +1,211 @@
+use super::camera::{Camera, Projection};
+use super::rules::Rule;
+use super::shape::{Point, Shape};
+use super::parse_named;
+use rand::{Rng, SeedableRng};
+
+/// Which part of the points of the probe the view is fitted to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitBounds {
+    /// The bounding box of all of the points
+    BoundingBox,
+    /// The box between the `p` and `1 - p` quantiles of the points along each axis, which ignores rare outliers
+    Percentile(f64),
+}
+
+/// Frames the attractor of a rule automatically, from the points visited by a short probe run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fit {
+    pub bounds: FitBounds,
+    /// Fraction of the width and height of the frame left empty on each side of the attractor
+    pub margin: f64,
+}
+
+impl Default for Fit {
+    fn default() -> Self {
+        Self {
+            bounds: FitBounds::BoundingBox,
+            margin: 0.05,
+        }
+    }
+}
+
+impl Fit {
+    /// Number of points that the probe collects after the burn-in
+    pub const PROBE_STEPS: usize = 200_000;
+
+    /// Creates a fitting mode from its name and its optional parameters
+    pub fn new(name: &str, params: &[f64]) -> Result<Self, String> {
+        let default = Self::default();
+
+        let (bounds, margin) = match (name, params) {
+            ("bbox", []) => (FitBounds::BoundingBox, default.margin),
+            ("bbox", [margin]) => (FitBounds::BoundingBox, *margin),
+            ("percentile", []) => (FitBounds::Percentile(0.001), default.margin),
+            ("percentile", [p]) => (FitBounds::Percentile(*p), default.margin),
+            ("percentile", [p, margin]) => (FitBounds::Percentile(*p), *margin),
+            ("bbox" | "percentile", _) => return Err(format!("Too many parameters for fitting mode '{}'", name)),
+            _ => return Err(format!("Unknown fitting mode '{}', expected either bbox or percentile", name)),
+        };
+
+        if let FitBounds::Percentile(p) = bounds {
+            if !(0.0..0.5).contains(&p) {
+                return Err(format!("Expected the percentile to be between 0 and 0.5, got {}", p));
+            }
+        }
+        if !(0.0..0.5).contains(&margin) {
+            return Err(format!("Expected the margin to be between 0 and 0.5, got {}", margin));
+        }
+
+        Ok(Self {bounds, margin})
+    }
+
+    /// Runs `rule` for `burnin_steps` and then `PROBE_STEPS`, and returns the scale, the center and the camera
+    /// that make the visited points, passed through `final_rule` if set, fill a `width × height` frame once projected by `camera`.
+    /// With a nonlinear projection, the center is still fitted as if the projection was linear, since it is the center of the projection;
+    /// the radius of the log-polar and polar projections is then chosen to center the log-radii of the points.
+    pub fn frame<R: Rule>(
+        &self,
+        (mut rule, mut final_rule): (R, Option<R>),
+        shape: &Shape,
+        burnin_steps: usize,
+        seed: Option<u64>,
+        camera: &Camera,
+        (width, height): (u32, u32),
+    ) -> (f64, (f64, f64), Camera) {
+        let mut rng = match seed {
+            Some(seed) => rand_xoshiro::Xoshiro256Plus::seed_from_u64(seed),
+            None => rand_xoshiro::Xoshiro256Plus::from_entropy(),
+        };
+        rule.reseed(&rng.gen());
+        if let Some(final_rule) = final_rule.as_mut() {
+            final_rule.reseed(&rng.gen());
+        }
+
+        let mut point = Point::new(0.0, 0.0, (0.0, 0.0, 0.0));
+        let mut history = vec![0; 4];
+        let mut points = Vec::with_capacity(Self::PROBE_STEPS);
+
+        for n in 0..burnin_steps + Self::PROBE_STEPS {
+            let (new_point, new_index) = rule.next(point, &history, shape, false);
+            point = new_point;
+            history.rotate_right(1);
+            history[0] = new_index;
+
+            if n >= burnin_steps {
+                let plotted = match final_rule.as_mut() {
+                    Some(final_rule) => final_rule.next(point, &history, shape, false).0,
+                    None => point,
+                };
+                points.push((plotted.x, plotted.y));
+            }
+        }
+
+        // The bounds are measured along the axes of the frame
+        let [a, b, c, d] = camera.matrix();
+        let to_frame = |points: &mut dyn Iterator<Item = (f64, f64)>| -> (Vec<f64>, Vec<f64>) {
+            points
+                .map(|(x, y)| (a * x + b * y, c * x + d * y))
+                .filter(|(x, y)| x.is_finite() && y.is_finite())
+                .unzip()
+        };
+
+        let (mut xs, mut ys) = to_frame(&mut points.iter().copied());
+        let (scale, center) = self.view(self.bounds.range(&mut xs), self.bounds.range(&mut ys), width, height);
+        let center = camera.inverse(center);
+
+        let mut camera = *camera;
+        let mut log_radii = points.iter()
+            .map(|(x, y)| (x - center.0).hypot(y - center.1).ln())
+            .filter(|r| r.is_finite())
+            .collect::<Vec<_>>();
+        let (min, max) = self.bounds.range(&mut log_radii);
+        let radius = ((min + max) / 2.0).exp();
+
+        match camera.projection {
+            Projection::Linear => return (scale, center, camera),
+            Projection::LogPolar {..} => camera.projection = Projection::LogPolar {radius},
+            Projection::Polar {..} => camera.projection = Projection::Polar {radius},
+            Projection::Fisheye {..} | Projection::Inversion {..} => {}
+        }
+        let projection = camera.projection;
+
+        // The projected points are fitted in a box centered on the frame
+        let (mut xs, mut ys) = to_frame(&mut points.iter().map(|(x, y)| projection.apply((x - center.0, y - center.1))));
+        let symmetric = |(min, max): (f64, f64)| {
+            let extent = min.abs().max(max.abs());
+            (-extent, extent)
+        };
+        let (scale, _) = self.view(symmetric(self.bounds.range(&mut xs)), symmetric(self.bounds.range(&mut ys)), width, height);
+
+        (scale, center, camera)
+    }
+
+    /// Returns the scale and the center that fit the box `x_range × y_range` in a `width × height` frame;
+    /// a scale of `s` maps `2 s` units to the smallest side of the frame
+    fn view(&self, x_range: (f64, f64), y_range: (f64, f64), width: u32, height: u32) -> (f64, (f64, f64)) {
+        let (width, height) = (width as f64, height as f64);
+        let center = ((x_range.0 + x_range.1) / 2.0, (y_range.0 + y_range.1) / 2.0);
+        // Degenerate attractors, like a single point or a line, still get a finite scale
+        let extent_x = (x_range.1 - x_range.0).max(1e-9);
+        let extent_y = (y_range.1 - y_range.0).max(1e-9);
+
+        let usable = 1.0 - 2.0 * self.margin;
+        let ratio = (width * usable / extent_x).min(height * usable / extent_y);
+        let scale = width.min(height) / ratio / 2.0;
+
+        (scale, center)
+    }
+}
+
+impl FitBounds {
+    /// Returns the range of `values` covered by these bounds; `values` gets sorted if needed
+    fn range(&self, values: &mut [f64]) -> (f64, f64) {
+        if values.is_empty() {
+            return (-1.0, 1.0);
+        }
+
+        match self {
+            Self::BoundingBox => values.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| (min.min(*x), max.max(*x))),
+            Self::Percentile(p) => {
+                values.sort_by(|a, b| a.total_cmp(b));
+                let last = values.len() - 1;
+                let low = (p * last as f64).round() as usize;
+                let high = ((1.0 - p) * last as f64).round() as usize;
+
+                (values[low], values[high])
+            }
+        }
+    }
+}
+
+/// Parses a fitting mode in the `name[:param...]` format, for instance `bbox:0.1` or `percentile:0.001:0.05`
+impl std::str::FromStr for Fit {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (name, params) = parse_named(raw)?;
+
+        Self::new(name, &params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fit() {
+        let fit = Fit {bounds: FitBounds::BoundingBox, margin: 0.0};
+        // A 4×2 box fills the width of a 200×100 frame
+        assert_eq!(fit.view((-1.0, 3.0), (0.0, 2.0), 200, 100), (1.0, (1.0, 1.0)));
+
+        let mut values = (0..=1000).map(|x| x as f64).collect::<Vec<_>>();
+        values.push(1e9);
+        assert_eq!(FitBounds::BoundingBox.range(&mut values), (0.0, 1e9));
+        assert_eq!(FitBounds::Percentile(0.01).range(&mut values), (10.0, 991.0));
+
+        assert_eq!("percentile:0.01:0.1".parse::<Fit>(), Ok(Fit {bounds: FitBounds::Percentile(0.01), margin: 0.1}));
+        assert!("bbox:0.6".parse::<Fit>().is_err());
+    }
+}
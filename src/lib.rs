@@ -2,12 +2,39 @@ pub const BG_R: f64 = 0.001;
 pub const BG_G: f64 = 0.001;
 pub const BG_B: f64 = 0.001;
 
-pub const GAMMA: f64 = 2.2;
+/// Splits a `name[:param:...]` argument into its name and numerical parameters
+pub(crate) fn parse_named(raw: &str) -> Result<(&str, Vec<f64>), String> {
+    let mut iter = raw.split(':');
+    let name = iter.next().unwrap_or("");
+    let params = iter
+        .map(|x| x.parse::<f64>().map_err(|e| format!("{:?}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((name, params))
+}
 
 pub mod shape;
 
+pub mod color;
+
 pub mod world;
 
+pub mod camera;
+
+pub mod tonemap;
+
+pub mod splat;
+
+pub mod filter;
+
+pub mod export;
+
+pub mod checkpoint;
+
+pub mod fit;
+
+pub mod scene;
+
 pub mod rules;
 
 #[cfg(feature = "box")]
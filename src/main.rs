@@ -1,5 +1,5 @@
 use pixels::{Pixels, SurfaceTexture};
-use clap::{arg, command};
+use clap::{arg, command, ArgMatches, Command};
 use winit::{
     dpi::LogicalSize,
     event::{Event, VirtualKeyCode},
@@ -7,10 +7,22 @@ use winit::{
     window::WindowBuilder
 };
 use winit_input_helper::WinitInputHelper;
-use std::time::Duration;
+use image::ImageFormat;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use chaos_game::{
+    camera::{Camera, Projection},
+    checkpoint::{self, CheckpointMeta},
+    color::ColorSpace,
+    export,
+    filter::{DensityEstimation, Filter, Supersample},
+    fit::Fit,
+    scene::{Layer, Scene},
     shape::*,
+    splat::Splat,
+    tonemap::{Background, Gain, ToneMap},
     world::*,
     rules::*,
     script::*
@@ -21,52 +33,269 @@ const WIDTH: u32 = 1024;
 const HEIGHT: u32 = 1024;
 const RESIZE: bool = true;
 
+// Interval between two progress reports in headless mode; the longer one is used if stderr isn't a terminal
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+const PROGRESS_INTERVAL_LOG: Duration = Duration::from_secs(10);
+
+/// Where the final image should be written to
+enum Output {
+    /// Write the image as PNG to stdout
+    Stdout,
+    File {
+        path: PathBuf,
+        format: ImageFormat,
+        overwrite: bool,
+    },
+}
+
+impl Output {
+    fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Stdout => None,
+            Self::File {path, ..} => Some(path),
+        }
+    }
+}
+
+/// Options controlling the execution of the program, rather than the render itself
+struct Options {
+    headless: bool,
+    max_steps: Option<usize>,
+    max_time: Option<Duration>,
+    output: Output,
+    /// Unset by --no-overwrite, which applies to every file written at the end of the render, even if the image goes to stdout
+    overwrite: bool,
+    /// Where to export the accumulation buffer, as OpenEXR if the extension is `.exr` and in the raw format otherwise
+    raw_output: Option<PathBuf>,
+    checkpoint: Option<CheckpointOptions>,
+    /// Where to save the relative error of each pixel, only available with the `sigma` feature
+    noise_map: Option<Output>,
+}
+
+/// Where and how often checkpoints are written in headless mode
+struct CheckpointOptions {
+    path: PathBuf,
+    interval: Duration,
+    meta: CheckpointMeta,
+}
+
+/// Options of the `merge` subcommand
+struct MergeOptions {
+    inputs: Vec<PathBuf>,
+    output: Output,
+    /// Unset by --no-overwrite, which applies to the image as well as the raw output
+    overwrite: bool,
+    raw_output: Option<PathBuf>,
+    /// Where to save the merged checkpoint
+    checkpoint: Option<PathBuf>,
+    gain: Gain,
+    tone_map: ToneMap,
+    /// Used to downsample supersampled checkpoints
+    filter: Filter,
+    density_estimation: Option<DensityEstimation>,
+    background: Background,
+}
+
 fn main() -> Result<(), pixels::Error> {
-    let (world, headless, max_steps) = handle_args();
+    let matches = parse_args();
 
-    if headless {
-        main_headless(world, max_steps);
+    if let Some(matches) = matches.subcommand_matches("merge") {
+        main_merge(merge_options(matches));
+        return Ok(());
+    }
+
+    let (scene, options) = handle_args(&matches);
+
+    check_overwrite(options.overwrite, [options.output.path(), options.raw_output.as_ref(), options.noise_map.as_ref().and_then(Output::path)]);
+
+    if options.headless {
+        main_headless(scene, options);
 
         Ok(())
     } else {
-        main_interactive(world, max_steps)
+        main_interactive(scene, options)
     }
 }
 
-fn main_headless(mut world: World, max_steps: Option<usize>) {
+fn main_headless(mut scene: Scene, options: Options) {
+    let Options {max_steps, max_time, ..} = options;
+    let checkpoint = options.checkpoint.as_ref();
+
     let (tx, rx) = std::sync::mpsc::channel();
 
     ctrlc::set_handler(move || tx.send(()).expect("Couldn't notify the main thread of ctrl-c")).expect("Error listening for ctrl-c");
 
+    let is_terminal = std::io::stderr().is_terminal();
+    let interval = if is_terminal {
+        PROGRESS_INTERVAL
+    } else {
+        PROGRESS_INTERVAL_LOG
+    };
+
+    let start = Instant::now();
+    let mut last_report = start;
+    let mut last_checkpoint = start;
+
     loop {
-        // world.update();
-        if let Ok(_) = rx.try_recv() {
+        if rx.try_recv().is_ok() {
             break
         }
         if let Some(max_steps) = max_steps {
-            if world.steps() >= max_steps {
+            if scene.steps() >= max_steps {
                 break
             }
         }
+        if let Some(max_time) = max_time {
+            if start.elapsed() >= max_time {
+                break
+            }
+        }
+        if scene.converged() {
+            break
+        }
+
+        if last_report.elapsed() >= interval {
+            last_report = Instant::now();
+            report_progress(scene.steps(), scene.noise(), start.elapsed(), max_steps, max_time, is_terminal);
+        }
+
+        if let Some(checkpoint) = checkpoint {
+            if last_checkpoint.elapsed() >= checkpoint.interval {
+                last_checkpoint = Instant::now();
+                if let Some(state) = scene.single_mut().and_then(|world| world.snapshot()) {
+                    if let Err(e) = checkpoint::save(&checkpoint.path, &checkpoint.meta, &state) {
+                        eprintln!("Couldn't save checkpoint: {}", e);
+                    }
+                }
+            }
+        }
+
         std::thread::sleep(Duration::new(0, 10_000_000));
     }
 
-    world.stop();
-    println!("{} iterations", world.steps());
-
-    let mut buffer = vec![0; world.width() as usize * world.height() as usize * 4];
-    world.draw(&mut buffer);
-    image::save_buffer(
-        "./output.png",
-        &buffer,
-        world.width(),
-        world.height(),
-        image::ColorType::Rgba8,
-    )
-    .expect("Couldn't save result to disk!");
+    scene.stop();
+    report_progress(scene.steps(), scene.noise(), start.elapsed(), max_steps, max_time, is_terminal);
+    if is_terminal {
+        eprintln!();
+    }
+    if scene.converged() {
+        eprintln!("The render converged");
+    }
+    eprintln!("{} iterations", scene.steps());
+
+    // Saved first, as it is what allows the render to be resumed
+    if let (Some(checkpoint), Some(state)) = (checkpoint, scene.single().and_then(|world| world.accumulation())) {
+        if let Err(e) = checkpoint::save(&checkpoint.path, &checkpoint.meta, state) {
+            eprintln!("Couldn't save checkpoint: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = save_output(&scene, &options.output) {
+        eprintln!("Couldn't save result: {}", e);
+        std::process::exit(1);
+    }
+
+    // The accumulation buffers of layered scenes aren't exported
+    let world = match scene.single() {
+        Some(world) => world,
+        None => return,
+    };
+
+    if let (Some(path), Some(state)) = (&options.raw_output, world.accumulation()) {
+        if let Err(e) = save_raw_output(&world.supersample().downsample(state, world.wrap()).to_linear(world.color_space()), path, options.overwrite) {
+            eprintln!("Couldn't save the accumulation buffer: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let (Some(output), Some(state)) = (&options.noise_map, world.accumulation()) {
+        let state = world.supersample().downsample(state, world.wrap());
+        let mut buffer = vec![0; state.width * state.height * 4];
+        state.draw_noise(&mut buffer);
+
+        if let Err(e) = save_image(&buffer, state.width as u32, state.height as u32, output) {
+            eprintln!("Couldn't save the noise map: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn main_interactive(mut world: World, max_steps: Option<usize>) -> Result<(), pixels::Error> {
+/// Sums the checkpoints of several renders of the same scene, then saves the result
+fn main_merge(options: MergeOptions) {
+    check_overwrite(options.overwrite, [options.output.path(), options.raw_output.as_ref()]);
+
+    let load = |path: &PathBuf| checkpoint::load(path).unwrap_or_else(|e| {
+        eprintln!("Couldn't read {}: {}", path.display(), e);
+        std::process::exit(1);
+    });
+
+    let (first, rest) = options.inputs.split_first().expect("Expected at least one checkpoint");
+    let (mut meta, mut state) = load(first);
+    let mut seeds = vec![meta.seed];
+
+    for path in rest {
+        let (other_meta, other_state) = load(path);
+
+        if let Err(e) = other_meta.check_compatible(&meta) {
+            eprintln!("Can't merge {} with {}: {}", path.display(), first.display(), e);
+            std::process::exit(1);
+        }
+        if other_meta.seed.is_some() && seeds.contains(&other_meta.seed) {
+            eprintln!("Warning: {} was rendered with the same seed as another checkpoint, their samples are likely identical", path.display());
+        }
+        seeds.push(other_meta.seed);
+
+        let (width, height) = (other_state.width, other_state.height);
+        if !state.combine(other_state) {
+            eprintln!(
+                "Can't merge {} with {}: the dimensions differ ({}x{}, expected {}x{})",
+                path.display(), first.display(), width, height, state.width, state.height
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // The merged render doesn't correspond to any single seed
+    meta.seed = None;
+    eprintln!("{} iterations", state.steps);
+
+    if let Some(path) = &options.checkpoint {
+        if let Err(e) = checkpoint::save(path, &meta, &state) {
+            eprintln!("Couldn't save checkpoint: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let supersample = Supersample {
+        factor: meta.supersample,
+        filter: options.filter,
+    };
+    let mut state = supersample.downsample(&state, meta.wrap);
+    if let Some(density_estimation) = options.density_estimation {
+        state = density_estimation.apply(&state, meta.wrap);
+    }
+
+    let mut buffer = vec![0; state.width * state.height * 4];
+    let gain = state.gain(options.gain, &options.tone_map);
+    state.draw(&mut buffer, gain, &options.tone_map, options.background, meta.color_space);
+
+    if let Err(e) = save_image(&buffer, state.width as u32, state.height as u32, &options.output) {
+        eprintln!("Couldn't save result: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &options.raw_output {
+        if let Err(e) = save_raw_output(&state.to_linear(meta.color_space), path, options.overwrite) {
+            eprintln!("Couldn't save the accumulation buffer: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main_interactive(mut scene: Scene, options: Options) -> Result<(), pixels::Error> {
+    let max_steps = options.max_steps;
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let window = {
@@ -89,7 +318,7 @@ fn main_interactive(mut world: World, max_steps: Option<usize>) -> Result<(), pi
 
     event_loop.run(move |event, _, control_flow| {
         if let Event::RedrawRequested(_) = event {
-            world.draw(pixels.get_frame());
+            scene.draw(pixels.get_frame());
 
             if pixels
                 .render()
@@ -105,22 +334,23 @@ fn main_interactive(mut world: World, max_steps: Option<usize>) -> Result<(), pi
             if
                 input.key_pressed(VirtualKeyCode::Escape)
                 || input.quit()
-                || max_steps.map(|m| world.steps() >= m).unwrap_or(false)
+                || max_steps.map(|m| scene.steps() >= m).unwrap_or(false)
             {
-                world.stop();
-                println!("{} iterations", world.steps());
+                scene.stop();
+                eprintln!("{} iterations", scene.steps());
                 *control_flow = ControlFlow::Exit;
 
-                let mut buffer = vec![0; world.width() as usize * world.height() as usize * 4];
-                world.draw(&mut buffer);
-                image::save_buffer(
-                    "./output.png",
-                    &buffer,
-                    world.width(),
-                    world.height(),
-                    image::ColorType::Rgba8,
-                )
-                .expect("Couldn't save result to disk!");
+                if let Err(e) = save_output(&scene, &options.output) {
+                    eprintln!("Couldn't save result: {}", e);
+                }
+
+                if let (Some(path), Some(world)) = (&options.raw_output, scene.single()) {
+                    if let Some(state) = world.accumulation() {
+                        if let Err(e) = save_raw_output(&world.supersample().downsample(state, world.wrap()).to_linear(world.color_space()), path, options.overwrite) {
+                            eprintln!("Couldn't save the accumulation buffer: {}", e);
+                        }
+                    }
+                }
 
                 return;
             }
@@ -129,11 +359,11 @@ fn main_interactive(mut world: World, max_steps: Option<usize>) -> Result<(), pi
                 pixels.resize_surface(size.width, size.height);
                 if RESIZE {
                     pixels.resize_buffer(size.width, size.height);
-                    world.resize(size.width, size.height);
+                    scene.resize(size.width, size.height);
                 }
             }
 
-            world.draw(pixels.get_frame());
+            scene.draw(pixels.get_frame());
 
             if pixels
                 .render()
@@ -147,6 +377,154 @@ fn main_interactive(mut world: World, max_steps: Option<usize>) -> Result<(), pi
     })
 }
 
+/// Exits if one of the outputs already exists and shouldn't be overwritten, before anything gets rendered
+fn check_overwrite<const N: usize>(overwrite: bool, paths: [Option<&PathBuf>; N]) {
+    if overwrite {
+        return;
+    }
+
+    for path in paths.into_iter().flatten() {
+        if path.exists() {
+            eprintln!("{} already exists, refusing to overwrite it", path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Writes an accumulation buffer to `path`
+fn save_raw_output(state: &State, path: &Path, overwrite: bool) -> std::io::Result<()> {
+    let file = create_file(path, overwrite)?;
+
+    if path.extension().map(|ext| ext.eq_ignore_ascii_case("exr")).unwrap_or(false) {
+        export::write_exr(state, file)
+    } else {
+        export::write_raw(state, file)
+    }
+}
+
+fn create_file(path: &Path, overwrite: bool) -> std::io::Result<std::fs::File> {
+    if overwrite {
+        std::fs::File::create(path)
+    } else {
+        // Fails if the file was created during the render
+        std::fs::OpenOptions::new().write(true).create_new(true).open(path)
+    }
+}
+
+/// Draws the current state of `scene` and writes it to `output`
+fn save_output(scene: &Scene, output: &Output) -> image::ImageResult<()> {
+    let mut buffer = vec![0; scene.width() as usize * scene.height() as usize * 4];
+    scene.draw(&mut buffer);
+
+    save_image(&buffer, scene.width(), scene.height(), output)
+}
+
+/// Writes an RGBA buffer to `output`
+fn save_image(buffer: &[u8], width: u32, height: u32, output: &Output) -> image::ImageResult<()> {
+    match output {
+        Output::Stdout => {
+            // The encoder needs to seek, so the image is first encoded in memory
+            let mut encoded = std::io::Cursor::new(Vec::new());
+            image::write_buffer_with_format(
+                &mut encoded,
+                buffer,
+                width,
+                height,
+                image::ColorType::Rgba8,
+                ImageFormat::Png,
+            )?;
+
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(encoded.get_ref())?;
+            stdout.flush()?;
+        }
+        Output::File {path, format, overwrite} => {
+            let file = create_file(path, *overwrite)?;
+
+            image::write_buffer_with_format(
+                &mut std::io::BufWriter::new(file),
+                buffer,
+                width,
+                height,
+                image::ColorType::Rgba8,
+                *format,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the progress of the render on stderr; if `overwrite` is true, then the previous report is overwritten
+fn report_progress(
+    steps: usize,
+    noise: Option<f64>,
+    elapsed: Duration,
+    max_steps: Option<usize>,
+    max_time: Option<Duration>,
+    overwrite: bool
+) {
+    let rate = steps as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+    let eta_steps = max_steps.filter(|_| rate > 0.0).map(|max_steps| {
+        Duration::from_secs_f64(max_steps.saturating_sub(steps) as f64 / rate)
+    });
+    let eta_time = max_time.map(|max_time| max_time.saturating_sub(elapsed));
+    let eta = match (eta_steps, eta_time) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    let mut report = format!("{} steps", format_int(steps));
+    if let Some(max_steps) = max_steps {
+        report += &format!(" / {} ({:.1}%)", format_int(max_steps), steps as f64 / max_steps as f64 * 100.0);
+    }
+    report += &format!(", {} steps/s, elapsed {}", format_int(rate as usize), format_duration(elapsed));
+    if let Some(eta) = eta {
+        report += &format!(", ETA {}", format_duration(eta));
+    }
+    if let Some(noise) = noise {
+        report += &format!(", noise {:.3e}", noise);
+    }
+
+    if overwrite {
+        // Trailing spaces erase any leftover from a longer, previous report
+        eprint!("\r{}    ", report);
+    } else {
+        eprintln!("{}", report);
+    }
+}
+
+/// Formats `value` with the same suffixes as `parse_int`
+fn format_int(value: usize) -> String {
+    const SUFFIXES: [(usize, &str); 4] = [
+        (1_000_000_000_000, "T"),
+        (1_000_000_000, "B"),
+        (1_000_000, "M"),
+        (1_000, "k"),
+    ];
+
+    for (mult, suffix) in SUFFIXES {
+        if value >= mult {
+            return format!("{:.2}{}", value as f64 / mult as f64, suffix);
+        }
+    }
+
+    format!("{}", value)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
 fn parse_int(raw: &str) -> Result<usize, std::num::ParseIntError> {
     let (raw, mult) = match raw.chars().last() {
         Some('k') | Some('K') => (&raw[0..(raw.len() - 1)], 1000),
@@ -159,6 +537,56 @@ fn parse_int(raw: &str) -> Result<usize, std::num::ParseIntError> {
     raw.parse::<usize>().map(|x| x * mult)
 }
 
+/// Parses a duration, given in seconds by default or with one of the `s`, `m`, `h` or `d` suffixes
+fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let (raw, mult) = match raw.chars().last() {
+        Some('s') => (&raw[0..(raw.len() - 1)], 1.0),
+        Some('m') => (&raw[0..(raw.len() - 1)], 60.0),
+        Some('h') => (&raw[0..(raw.len() - 1)], 3600.0),
+        Some('d') => (&raw[0..(raw.len() - 1)], 86400.0),
+        _ => (raw, 1.0)
+    };
+
+    let secs = raw.parse::<f64>().map_err(|e| format!("{:?}", e))? * mult;
+
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{:?}", e))
+}
+
+/// Parses the output path, with the format deduced from its extension; `-` stands for stdout
+fn parse_output(raw: &str, overwrite: bool) -> Result<Output, String> {
+    if raw == "-" {
+        return Ok(Output::Stdout);
+    }
+
+    let path = PathBuf::from(raw);
+    let format = match ImageFormat::from_path(&path) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Tiff | ImageFormat::Bmp)) => format,
+        _ => return Err(String::from("Unsupported output format, expected one of png, jpg, webp, tiff or bmp")),
+    };
+
+    Ok(Output::File {
+        path,
+        format,
+        overwrite,
+    })
+}
+
+/// Returns the background set with --background, or the default one
+fn parse_background(matches: &ArgMatches) -> Background {
+    matches.value_of("background").map(|s| s.parse::<Background>().unwrap()).unwrap_or_default()
+}
+
+/// Parses stretch factors in the `x:y` format
+fn parse_stretch(raw: &str) -> Result<(f64, f64), String> {
+    let factors = raw
+        .split(':')
+        .map(|x| x.parse::<f64>().map_err(|e| format!("{:?}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let settings = [(String::from("stretch"), factors)];
+    Camera::new(&settings).map(|camera| camera.stretch)
+}
+
 fn parse_dim(raw: &str) -> Result<(u32, u32), String> {
     let mut iter = raw.split("x");
     let first = iter.next().ok_or(String::from("Expected a non-empty value"))?;
@@ -170,8 +598,8 @@ fn parse_dim(raw: &str) -> Result<(u32, u32), String> {
     ))
 }
 
-fn handle_args() -> (World, bool, Option<usize>) {
-    let matches = command!()
+fn parse_args() -> ArgMatches {
+    command!()
         .arg(arg!([input] "The input script to run"))
         .arg(arg!(--headless "Whether to run in headless mode").required(false))
         .arg(
@@ -181,14 +609,66 @@ fn handle_args() -> (World, bool, Option<usize>) {
             .validator(|s| s.parse::<usize>())
         )
         .arg(arg!(--scale <VALUE> "The default scale factor, ignored if set by the input script").required(false).default_value("1.25").validator(|s| s.parse::<f64>()))
+        .arg(
+            arg!(--projection <NAME> "How points are projected around the center of the view: linear, log-polar[:radius], polar[:radius] (angle on x, log-radius on y), fisheye[:radius] or inversion[:radius]; ignored if the input script sets CAMERA")
+            .required(false)
+            .default_value("linear")
+            .validator(|s| s.parse::<Projection>())
+        )
+        .arg(
+            arg!(--rotation <DEGREES> "Rotate the image counterclockwise around the center of the view; ignored if the input script sets CAMERA")
+            .required(false)
+            .allow_hyphen_values(true)
+            .validator(|s| s.parse::<f64>())
+        )
+        .arg(
+            arg!(--stretch <FACTORS> "Stretch the image along the horizontal and vertical axes of the frame, as x:y; ignored if the input script sets CAMERA")
+            .required(false)
+            .validator(parse_stretch)
+        )
+        .arg(arg!(--"flip-x" "Mirror the image horizontally; ignored if the input script sets CAMERA").required(false))
+        .arg(arg!(--"flip-y" "Mirror the image vertically; ignored if the input script sets CAMERA").required(false))
+        .arg(arg!(--wrap "Wrap the points that fall outside of the frame around to the opposite edge, so that the image tiles seamlessly; also enabled by (define WRAP T)").required(false))
+        .arg(
+            arg!(--fit [MODE] "Frame the attractor automatically from a short probe run, overriding the scale and center: bbox[:margin] fits its bounding box, percentile[:p[:margin]] ignores the outermost fraction p of the points along each axis. Defaults to bbox:0.05; also enabled by (define SCALE 'auto)")
+            .required(false)
+            .validator(|s| s.parse::<Fit>())
+        )
         .arg(arg!(--steps <VALUE> "Number of steps between an update, defaults to 25k in normal mode and 10M in headless mode").required(false).validator(|s| parse_int(s)))
         .arg(arg!(--"scatter-steps" <VALUE> "Number of substeps that will act as 'scatter' for each step, defaults to 3 in normal mode and 7 in headless mode").required(false).validator(|s| parse_int(s)))
         .arg(arg!(--"max-steps" <VALUE> "Stop the program if max-steps is reached").required(false))
+        .arg(
+            arg!(--"max-time" <VALUE> "Stop the program after that much time, in seconds or suffixed with s, m, h or d; only valid in headless mode")
+            .required(false)
+            .validator(parse_duration)
+        )
+        .arg(
+            arg!(--"target-mse" <VALUE> "Stop the program once the mean squared error of the lightness of the pixels falls below this value; needs the sigma feature and headless mode")
+            .required(false)
+            .validator(|s| s.parse::<f64>())
+        )
+        .arg(
+            arg!(--"target-noise" <VALUE> "Stop the program once the standard error of the lightness of the pixels, relative to their lightness, falls below this value (for instance 0.01); needs the sigma feature and headless mode")
+            .required(false)
+            .conflicts_with("target-mse")
+            .validator(|s| s.parse::<f64>())
+        )
+        .arg(
+            arg!(--"noise-map" <PATH> "Also save an image of the relative error of each pixel, from black (converged) to white, to find the regions that converge slowly; needs the sigma feature and headless mode")
+            .required(false)
+            .validator(|s| parse_output(s, true))
+        )
         .arg(
             arg!(--"queue-length" <VALUE> "Maximum number of results that can sit in the queue; decrease if the program runs out of memory, increase if the queue becomes a bottleneck. Defaults to 2*num_cpus in normal mode and num_cpus in headless mode")
             .required(false)
             .validator(|s| parse_int(s))
         )
+        .arg(
+            arg!(--accumulation <MODE> "How the threads accumulate their results: dense gives each thread a buffer of the size of the image, sparse[:batch_size] sends the points by bounded batches instead, which uses less memory for large images")
+            .required(false)
+            .default_value("dense")
+            .validator(|s| s.parse::<Accumulation>())
+        )
         .arg(
             arg!(--threads <VALUE> "Number of threads; defaults to the number of CPU logical cores")
             .required(false)
@@ -207,25 +687,135 @@ fn handle_args() -> (World, bool, Option<usize>) {
             .default_value("100")
             .validator(|s| s.parse::<usize>())
         )
-        .get_matches();
+        .arg(
+            arg!(--seed <VALUE> "Seed of the random number generators; a given seed, number of threads and max-steps always yield the same image. Ignored if set by the input script")
+            .required(false)
+            .validator(|s| s.parse::<u64>())
+        )
+        .arg(
+            arg!(--gain <VALUE> "Scales the density of the pixels before tone mapping; either a number or auto[:percentile[:target]], which brightens the lit pixels at that percentile of density up to the target opacity. Ignored if set by the input script")
+            .required(false)
+            .default_value("0.1")
+            .validator(|s| s.parse::<Gain>())
+            .global(true)
+        )
+        .arg(
+            arg!(--"tone-map" <VALUE> "How densities are mapped to opacity: exp, log[:brightness[:contrast]], linear or hist; ignored if set by the input script")
+            .required(false)
+            .default_value("exp")
+            .validator(|s| s.parse::<ToneMap>())
+            .global(true)
+        )
+        .arg(
+            arg!(--splat <MODE> "How each point is spread over the pixels around it: nearest, bilinear or gaussian[:sigma], with sigma in pixels; ignored if set by the input script")
+            .required(false)
+            .default_value("nearest")
+            .validator(|s| s.parse::<Splat>())
+        )
+        .arg(
+            arg!(--supersample <N> "Accumulate at N times the resolution of the image along each axis, then downsample the result with --filter")
+            .required(false)
+            .default_value("1")
+            .validator(|s| match s.parse::<usize>() {
+                Ok(0) => Err(String::from("Expected a positive factor")),
+                x => x.map(|_| ()).map_err(|e| format!("{:?}", e)),
+            })
+        )
+        .arg(
+            arg!(--filter <FILTER> "Filter used to downsample supersampled renders: box, lanczos or mitchell")
+            .required(false)
+            .default_value("box")
+            .validator(|s| s.parse::<Filter>())
+            .global(true)
+        )
+        .arg(
+            arg!(--"density-estimation" <PARAMS> "Blur each pixel before tone mapping with a radius shrinking as its density grows, smoothing out sparse areas: max_radius[:min_radius[:curve]], where the radius is max_radius / density^curve (curve defaults to 0.4). Ignored if set by the input script")
+            .required(false)
+            .validator(|s| s.parse::<DensityEstimation>())
+            .global(true)
+        )
+        .arg(
+            arg!(--background <COLOR> "Color that the image is drawn over, as #rrggbb or r,g,b in sRGB; `transparent` instead stores the opacity of each pixel in its alpha channel. Defaults to a near-black gray, ignored if set by the input script")
+            .required(false)
+            .validator(|s| s.parse::<Background>())
+            .global(true)
+        )
+        .arg(
+            arg!(--"color-space" <SPACE> "Color space in which the colors of the points are blended and accumulated: linear, or oklab for perceptually even blends; ignored if set by the input script")
+            .required(false)
+            .default_value("linear")
+            .validator(|s| s.parse::<ColorSpace>())
+        )
+        .arg(
+            arg!(-o --output <PATH> "Where to save the final image; its format is chosen from the extension (png, jpg, webp, tiff or bmp), and `-` writes a PNG to stdout")
+            .required(false)
+            .default_value("output.png")
+            .validator(|s| parse_output(s, true))
+            .global(true)
+        )
+        .arg(
+            arg!(--"raw-output" <PATH> "Also export the accumulation buffer before tone mapping: as 32-bit OpenEXR if the extension is .exr, and in a raw 64-bit float format otherwise (see src/export.rs)")
+            .required(false)
+            .global(true)
+        )
+        .arg(
+            arg!(--checkpoint <PATH> "Periodically save the progress of the render to this file, as well as when it stops, so that it can be resumed with --resume; only valid in headless mode. With merge, where the merged checkpoint is saved")
+            .required(false)
+            .global(true)
+        )
+        .arg(
+            arg!(--"checkpoint-interval" <VALUE> "Time between two checkpoints, in seconds or suffixed with s, m, h or d")
+            .required(false)
+            .default_value("10m")
+            .validator(parse_duration)
+        )
+        .arg(
+            arg!(--resume <PATH> "Keep accumulating into this checkpoint, which is also where new checkpoints are saved unless --checkpoint is given. Refuses to resume if the script, the dimensions or the view changed; only valid in headless mode")
+            .required(false)
+        )
+        .arg(arg!(--"no-overwrite" "Refuse to overwrite the output file if it already exists").required(false).global(true))
+        .subcommand(
+            Command::new("merge")
+                .about("Sums checkpoints of the same scene rendered separately, for instance on different machines, and saves the result")
+                .arg(arg!(<checkpoints> ... "The checkpoints to merge; they must share the script, the dimensions, the view and the settings that change what is accumulated, such as --splat, --scatter-steps, --burnin and --polygon"))
+        )
+        .get_matches()
+}
+
+fn merge_options(matches: &ArgMatches) -> MergeOptions {
+    MergeOptions {
+        inputs: matches.values_of("checkpoints").unwrap().map(PathBuf::from).collect(),
+        output: parse_output(matches.value_of("output").unwrap(), matches.occurrences_of("no-overwrite") == 0).unwrap(),
+        overwrite: matches.occurrences_of("no-overwrite") == 0,
+        raw_output: matches.value_of("raw-output").map(PathBuf::from),
+        checkpoint: matches.value_of("checkpoint").map(PathBuf::from),
+        gain: matches.value_of("gain").unwrap().parse::<Gain>().unwrap(),
+        tone_map: matches.value_of("tone-map").unwrap().parse::<ToneMap>().unwrap(),
+        filter: matches.value_of("filter").unwrap().parse::<Filter>().unwrap(),
+        density_estimation: matches.value_of("density-estimation").map(|s| s.parse::<DensityEstimation>().unwrap()),
+        background: parse_background(matches),
+    }
+}
 
+fn handle_args(matches: &ArgMatches) -> (Scene, Options) {
     // Execute input script
     let script = std::fs::read_to_string(
         matches.value_of("input").unwrap_or("rule.lisp")
     ).unwrap();
-    let (rule, shape, scale, center) = eval_rule(&script).unwrap();
+    let seed = matches.value_of("seed").map(|s| s.parse::<u64>().unwrap());
+    let ScriptResult {rule, final_rule, shape, scale, fit, center, camera, seed, tone_map, gain, splat, density_estimation, background, color_space, wrap, layers} = eval_rule(&script, seed).unwrap();
 
     // Extract rule
     let rule = rule.unwrap_or(BoxedRule::new(DefaultRule::default()));
 
     // Extract shape
-    let shape = if let Some(shape) = shape {
-        shape
+    let (shape, n_sides) = if let Some(shape) = shape {
+        (shape, None)
     } else {
         let color_a = from_srgb(160, 147, 242);
         let color_b = from_srgb(186, 190, 220);
         let n_sides: usize = matches.value_of("polygon").unwrap().parse::<usize>().unwrap();
-        colorize(polygon(n_sides), color_a, color_b, (n_sides / 2).max(1))
+        (colorize(polygon(n_sides), color_a, color_b, (n_sides / 2).max(1)), Some(n_sides))
     };
 
     // Extract scale
@@ -234,6 +824,43 @@ fn handle_args() -> (World, bool, Option<usize>) {
     // Extract center
     let center = center.unwrap_or((0.0, 0.0));
 
+    // Extract camera
+    let camera = camera.unwrap_or_else(|| Camera {
+        projection: matches.value_of("projection").unwrap().parse::<Projection>().unwrap(),
+        rotation: matches.value_of("rotation").map(|s| s.parse::<f64>().unwrap()).unwrap_or(0.0),
+        stretch: matches.value_of("stretch").map(|s| parse_stretch(s).unwrap()).unwrap_or((1.0, 1.0)),
+        flip_x: matches.occurrences_of("flip-x") > 0,
+        flip_y: matches.occurrences_of("flip-y") > 0,
+    });
+
+    // Extract fitting mode
+    let fit = fit.or_else(|| {
+        (matches.occurrences_of("fit") > 0).then(|| matches.value_of("fit").map(|s| s.parse::<Fit>().unwrap()).unwrap_or_default())
+    });
+
+    // Extract tone map
+    let tone_map = tone_map.unwrap_or(matches.value_of("tone-map").unwrap().parse::<ToneMap>().unwrap());
+
+    // Extract gain
+    let gain = gain.unwrap_or(matches.value_of("gain").unwrap().parse::<Gain>().unwrap());
+
+    // Extract splatting mode
+    let splat = splat.unwrap_or(matches.value_of("splat").unwrap().parse::<Splat>().unwrap());
+
+    // Extract background
+    let background = background.unwrap_or_else(|| parse_background(matches));
+
+    // Extract color space
+    let color_space = color_space.unwrap_or(matches.value_of("color-space").unwrap().parse::<ColorSpace>().unwrap());
+
+    // Extract wrap-around mode
+    let wrap = wrap.unwrap_or(matches.occurrences_of("wrap") > 0);
+
+    // Extract density estimation
+    let density_estimation = density_estimation.or_else(|| {
+        matches.value_of("density-estimation").map(|s| s.parse::<DensityEstimation>().unwrap())
+    });
+
     let headless = matches.occurrences_of("headless") > 0;
 
     let steps = parse_int(matches.value_of("steps").unwrap_or(if headless {
@@ -248,29 +875,197 @@ fn handle_args() -> (World, bool, Option<usize>) {
         "3"
     })).unwrap();
 
-    // TODO: rename zoom to scale
-    let params = WorldParams {
+    let max_steps = matches.value_of("max-steps").map(|s| parse_int(s).expect("Invalid value for max-steps"));
+    let max_time = matches.value_of("max-time").map(|s| parse_duration(s).unwrap());
+
+    let convergence = match (matches.value_of("target-mse"), matches.value_of("target-noise")) {
+        (Some(target), _) => Some(Convergence::Mse(target.parse::<f64>().unwrap())),
+        (_, Some(target)) => Some(Convergence::RelativeNoise(target.parse::<f64>().unwrap())),
+        _ => None,
+    }.filter(|_| headless);
+    let noise_map = matches.value_of("noise-map")
+        .filter(|_| headless)
+        .map(|s| parse_output(s, matches.occurrences_of("no-overwrite") == 0).unwrap());
+
+    if !cfg!(feature = "sigma") && (convergence.is_some() || noise_map.is_some()) {
+        eprintln!("--target-mse, --target-noise and --noise-map need the noise estimates of the sigma feature; rebuild with `--features sigma`");
+        std::process::exit(1);
+    }
+
+    let (width, height) = parse_dim(matches.value_of("dim").unwrap()).unwrap();
+
+    let supersample = Supersample {
+        factor: matches.value_of("supersample").unwrap().parse::<usize>().unwrap(),
+        filter: matches.value_of("filter").unwrap().parse::<Filter>().unwrap(),
+    };
+
+    let burnin_steps = matches.value_of("burnin").unwrap().parse::<usize>().unwrap();
+    let resume = matches.value_of("resume").filter(|_| headless).map(PathBuf::from);
+
+    let raw_output = matches.value_of("raw-output").map(PathBuf::from);
+
+    if layers.is_some() && (resume.is_some() || matches.value_of("checkpoint").is_some() || raw_output.is_some() || noise_map.is_some()) {
+        eprintln!("The accumulation buffers of a scene with LAYERS can't be saved, so --checkpoint, --resume, --raw-output and --noise-map aren't supported");
+        std::process::exit(1);
+    }
+
+    // Fit the view to the attractor, or to that of the first layer; a resumed render keeps the view of its checkpoint instead
+    let (fit_rule, fit_shape) = match layers.as_ref().and_then(|layers| layers.first()) {
+        Some(layer) => (&layer.rule, &layer.shape),
+        None => (&rule, &shape),
+    };
+    let (scale, center, camera) = match fit {
+        Some(fit) if resume.is_none() => {
+            let (scale, center, camera) = fit.frame((fit_rule.clone(), final_rule.clone()), fit_shape, burnin_steps, seed, &camera, (width, height));
+            eprintln!("Fitted view: scale {}, center ({}, {})", scale, center.0, center.1);
+            if camera.projection != Projection::Linear {
+                eprintln!("Fitted projection: {:?}", camera.projection);
+            }
+            (scale, center, camera)
+        }
+        _ => (scale, center, camera),
+    };
+
+    let mut meta = CheckpointMeta {
+        script_hash: checkpoint::script_hash(&script),
+        seed,
         zoom: scale,
         center,
+        camera,
+        wrap,
+        supersample: supersample.factor,
+        color_space,
+        polygon: n_sides,
+        splat,
+        scatter_steps,
+        burnin_steps,
+    };
+
+    // Load the checkpoint to resume from
+    let resumed = resume.map(|path| {
+        let exit = |message: String| -> ! {
+            eprintln!("Can't resume from {}: {}", path.display(), message);
+            std::process::exit(1);
+        };
+
+        let (resumed_meta, state) = checkpoint::load(&path).unwrap_or_else(|e| exit(e.to_string()));
+        if fit.is_some() {
+            meta.zoom = resumed_meta.zoom;
+            meta.center = resumed_meta.center;
+            meta.camera = resumed_meta.camera;
+        }
+
+        if let Err(e) = meta.check_compatible(&resumed_meta) {
+            exit(e);
+        }
+        let (state_width, state_height) = (state.width / supersample.factor, state.height / supersample.factor);
+        if matches.occurrences_of("dim") > 0 && (state_width, state_height) != (width as usize, height as usize) {
+            exit(format!("the dimensions changed ({}x{}, expected {}x{})", width, height, state_width, state_height));
+        }
+
+        (path, state)
+    });
+
+    // Don't replay the random streams that were already accumulated into the checkpoint
+    let seed = match &resumed {
+        Some((_, state)) => seed.map(|seed| seed ^ (state.steps as u64).wrapping_mul(0x9e3779b97f4a7c15)),
+        None => seed,
+    };
+    meta.seed = seed;
+
+    // TODO: rename zoom to scale
+    let params = WorldParams {
+        zoom: meta.zoom,
+        center: meta.center,
+        camera: meta.camera,
+        wrap,
         rule: RuleBox::new(rule),
+        final_rule: final_rule.map(RuleBox::new),
         shape,
         steps,
         scatter_steps,
-        burnin_steps: matches.value_of("burnin").unwrap().parse::<usize>().unwrap(),
-        gain: 0.1, // TODO: add parameter
+        burnin_steps,
+        gain,
+        tone_map,
+        seed,
+        max_steps,
+        accumulation: matches.value_of("accumulation").unwrap().parse::<Accumulation>().unwrap(),
+        splat,
+        supersample,
+        density_estimation,
+        background,
+        color_space,
+        convergence,
     };
 
     let n_threads = parse_int(matches.value_of("threads").unwrap()).unwrap();
 
-    let max_steps = matches.value_of("max-steps").map(|s| parse_int(s).expect("Invalid value for max-steps"));
-
-    let (width, height) = parse_dim(matches.value_of("dim").unwrap()).unwrap();
-
     let queue_length = matches.value_of("queue-length").map(|x| x.parse::<usize>().unwrap()).unwrap_or(if headless {
         num_cpus::get()
     } else {
         2 * num_cpus::get()
     });
 
-    (World::new(width, height, params, n_threads, queue_length), headless, max_steps)
+    let output = parse_output(
+        matches.value_of("output").unwrap(),
+        matches.occurrences_of("no-overwrite") == 0
+    ).unwrap();
+
+    let checkpoint_path = matches.value_of("checkpoint")
+        .map(PathBuf::from)
+        .or_else(|| resumed.as_ref().map(|(path, _)| path.clone()));
+    let checkpoint = checkpoint_path.filter(|_| headless).map(|path| CheckpointOptions {
+        path,
+        interval: parse_duration(matches.value_of("checkpoint-interval").unwrap()).unwrap(),
+        meta,
+    });
+
+    // Each layer stops at its own budget, so the whole scene is done once their sum is reached
+    let scene_max_steps = match &layers {
+        Some(layers) => layers.iter().map(|layer| layer.steps.or(max_steps)).sum::<Option<usize>>(),
+        None => max_steps,
+    };
+
+    let options = Options {
+        headless,
+        max_steps: scene_max_steps,
+        max_time,
+        output,
+        overwrite: matches.occurrences_of("no-overwrite") == 0,
+        raw_output,
+        checkpoint,
+        noise_map,
+    };
+
+    let scene = match (layers, resumed) {
+        (Some(layers), _) => {
+            let n_layers = layers.len();
+            let layers = layers.into_iter().enumerate().map(|(index, layer)| {
+                let mut layer_params = params.clone();
+                layer_params.rule = RuleBox::new(layer.rule);
+                layer_params.shape = layer.shape;
+                layer_params.max_steps = layer.steps.or(max_steps);
+                layer_params.gain = layer.gain.unwrap_or(params.gain);
+                layer_params.tone_map = layer.tone_map.unwrap_or_else(|| params.tone_map.clone());
+                // The scene blends the composited layers over its background itself
+                layer_params.background = Background::Transparent;
+                // Layers drawing the same rule shouldn't get the same random streams
+                layer_params.seed = seed.map(|seed| seed ^ (index as u64).wrapping_mul(0x9e3779b97f4a7c15));
+
+                // The layers render at the same time, so they share the threads
+                let layer_threads = (n_threads / n_layers + usize::from(index < n_threads % n_layers)).max(1);
+
+                Layer {
+                    world: World::new(width, height, layer_params, layer_threads, queue_length),
+                    blend: layer.blend,
+                }
+            }).collect();
+
+            Scene::Layered {layers, background}
+        }
+        (None, Some((_, state))) => Scene::Single(Box::new(World::with_state(state, params, n_threads, queue_length))),
+        (None, None) => Scene::Single(Box::new(World::new(width, height, params, n_threads, queue_length))),
+    };
+
+    (scene, options)
 }
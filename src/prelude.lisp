@@ -40,12 +40,6 @@
     l
 )))
 
-(defun srgb (r g b) (let ((GAMMA 2.2)) (list
-    (pow (/ (float r) 255.0) GAMMA)
-    (pow (/ (float g) 255.0) GAMMA)
-    (pow (/ (float b) 255.0) GAMMA)
-)))
-
 (defun interpolate (a b n) (map
     (lambda (i) (let
         ((x (/ (float i) (- (float n) 1.0)))) ; Ratio between a and b
@@ -1,6 +1,7 @@
 use super::shape::*;
 use rand::{Rng, SeedableRng};
 use rand_distr::Distribution;
+use std::cell::RefCell;
 
 #[cfg(feature = "box")]
 use dyn_clone::{DynClone, clone_box};
@@ -25,8 +26,20 @@ pub use transform::*;
 pub mod choice;
 pub use choice::*;
 
+pub mod variation;
+pub use variation::*;
+
+pub mod complex;
+pub use complex::*;
+
 type RuleInnerRng = rand_xoshiro::Xoshiro256Plus;
 
+thread_local! {
+    /// Source of the instance seeds of `RuleRng::new`: since the workers reseed their rule before running it,
+    /// building the same rule tree twice yields the same random streams for a given worker seed
+    static INSTANCE_SEEDS: RefCell<rand_xoshiro::SplitMix64> = RefCell::new(rand_xoshiro::SplitMix64::seed_from_u64(0));
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct RuleRng {
     pub instance_seed: [u8; 32],
@@ -112,6 +125,19 @@ pub trait Choice: DynClone + Send {
 }
 
 impl RuleRng {
+    /// Creates a new `RuleRng`, drawing its instance seed from a deterministic, thread-local source.
+    /// Use `RuleRng::reset_instance_seeds` before building a rule tree for it to be reproducible.
+    pub fn new() -> Self {
+        INSTANCE_SEEDS.with(|seeds| Self::from_rng(&mut *seeds.borrow_mut()).unwrap())
+    }
+
+    /// Resets the source of instance seeds of the current thread
+    pub fn reset_instance_seeds() {
+        INSTANCE_SEEDS.with(|seeds| {
+            *seeds.borrow_mut() = rand_xoshiro::SplitMix64::seed_from_u64(0);
+        });
+    }
+
     #[inline]
     pub fn reseed(&mut self, seed: &[u8; 32]) {
         for (instance_byte, seed_byte) in self.instance_seed.iter_mut().zip(seed.iter().copied()) {
@@ -122,6 +148,12 @@ impl RuleRng {
     }
 }
 
+impl Default for RuleRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for RuleRng {
     fn clone(&self) -> Self {
         Self {
@@ -244,6 +276,24 @@ impl<C: Choice> Rule for DefaultRule<C> {
     }
 }
 
+/// Leaves the point untouched, keeping the last index of the history; useful as the inner rule of a final transform
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdentityRule;
+
+impl Rule for IdentityRule {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        _shape: &Shape,
+        _scatter: bool,
+    ) -> (Point, usize) {
+        (previous, history.first().copied().unwrap_or(0))
+    }
+
+    fn reseed(&mut self, _seed: &[u8; 32]) {}
+}
+
 #[cfg(feature = "box")]
 mod rule_box {
     use super::*;
@@ -343,4 +393,40 @@ mod test {
             assert!(rng_reseed.gen::<u64>() == rng_reseed2.gen::<u64>());
         }
     }
+
+    #[test]
+    fn test_instance_seeds() {
+        let shape = polygon(5);
+        let seed = rand::thread_rng().gen();
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            RuleRng::reset_instance_seeds();
+            let mut rule = OrRule::new(DefaultRule::default(), DefaultRule::default(), 0.5, 0.5);
+            rule.reseed(&seed);
+
+            let mut point = Point::new(0.0, 0.0, (0.0, 0.0, 0.0));
+            let mut indices = Vec::new();
+            for _ in 0..100 {
+                let (next, index) = rule.next(point, &[0], &shape, false);
+                point = next;
+                indices.push(index);
+            }
+            results.push(indices);
+        }
+
+        assert!(results[0] == results[1]);
+    }
+
+    #[test]
+    fn test_identity_rule() {
+        let shape = polygon(5);
+        let point = Point::new(0.3, -0.2, (0.1, 0.2, 0.3));
+
+        let (next, index) = IdentityRule.next(point, &[3, 1], &shape, false);
+        assert!(next == point);
+        assert_eq!(index, 3);
+
+        assert_eq!(IdentityRule.next(point, &[], &shape, true).1, 0);
+    }
 }
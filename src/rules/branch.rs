@@ -11,7 +11,7 @@ pub struct OrRule<Left: Rule, Right: Rule> {
 impl<Left: Rule, Right: Rule> OrRule<Left, Right> {
     pub fn new(left: Left, right: Right, p: f64, p_scatter: f64) -> Self {
         Self {
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             left: RuleBox::new(left),
             right: RuleBox::new(right),
             p,
@@ -73,3 +73,181 @@ impl<Left: Rule, Right: Rule> Rule for OrRule<Left, Right> {
         self.right.reseed(seed);
     }
 }
+
+/// Walker's alias table, which samples one of `n` weighted outcomes in constant time
+#[derive(Clone, Debug)]
+pub struct AliasTable {
+    /// The weights, normalized to sum to 1
+    probabilities: Vec<f64>,
+    /// The probability of keeping the outcome `i` once the column `i` is drawn, rather than switching to `alias[i]`
+    keep: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> Result<Self, String> {
+        if let Some(weight) = weights.iter().find(|w| !w.is_finite() || **w < 0.0) {
+            return Err(format!("Expected the weights to be nonnegative, got {}", weight));
+        }
+        let total = weights.iter().sum::<f64>();
+        if total <= 0.0 {
+            return Err(String::from("Expected at least one positive weight"));
+        }
+
+        let n = weights.len();
+        let probabilities = weights.iter().map(|w| w / total).collect::<Vec<_>>();
+        let mut keep = probabilities.iter().map(|p| p * n as f64).collect::<Vec<_>>();
+        let mut alias = (0..n).collect::<Vec<_>>();
+
+        // Each column below 1 is filled up with the excess of a column above 1
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|&i| keep[i] < 1.0);
+        while let (Some(&s), Some(&l)) = (small.last(), large.last()) {
+            small.pop();
+            alias[s] = l;
+            keep[l] -= 1.0 - keep[s];
+            if keep[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        // The remaining columns are full, up to rounding errors
+        for i in small.into_iter().chain(large) {
+            keep[i] = 1.0;
+        }
+
+        Ok(Self {probabilities, keep, alias})
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keep.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keep.is_empty()
+    }
+
+    /// The probability of sampling the outcome `i`
+    #[inline]
+    pub fn probability(&self, i: usize) -> f64 {
+        self.probabilities[i]
+    }
+
+    #[inline]
+    pub fn sample<G: Rng>(&self, rng: &mut G) -> usize {
+        let i = rng.gen_range(0..self.len());
+        if rng.gen::<f64>() < self.keep[i] {
+            i
+        } else {
+            self.alias[i]
+        }
+    }
+}
+
+/// Picks one of several rules at random, according to their weights; this is the n-ary version of `OrRule`.
+/// Scatter steps draw the rules according to separate weights, and the weight of their points is corrected
+/// so that each rule keeps the importance given by its main weight.
+pub struct WeightedRule<R: Rule> {
+    rng: RuleRng,
+    rules: Vec<RuleBox<R>>,
+    weights: AliasTable,
+    scatter_weights: AliasTable,
+}
+
+impl<R: Rule> WeightedRule<R> {
+    /// `scatter_weights` defaults to `weights`, and must be positive wherever `weights` is
+    pub fn new(rules: Vec<R>, weights: &[f64], scatter_weights: Option<&[f64]>) -> Result<Self, String> {
+        let scatter_weights = scatter_weights.unwrap_or(weights);
+        if rules.len() != weights.len() || rules.len() != scatter_weights.len() {
+            return Err(format!(
+                "Expected as many weights as rules, got {} rules, {} weights and {} scatter weights",
+                rules.len(), weights.len(), scatter_weights.len()
+            ));
+        }
+
+        let weights = AliasTable::new(weights)?;
+        let scatter_weights = AliasTable::new(scatter_weights)?;
+        if (0..weights.len()).any(|i| weights.probability(i) > 0.0 && scatter_weights.probability(i) == 0.0) {
+            return Err(String::from("Expected the scatter weight of every rule with a positive weight to be positive"));
+        }
+
+        Ok(Self {
+            rng: RuleRng::new(),
+            rules: rules.into_iter().map(RuleBox::new).collect(),
+            weights,
+            scatter_weights,
+        })
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &R> {
+        self.rules.iter().map(|rule| &**rule)
+    }
+}
+
+impl<R: Rule> Clone for WeightedRule<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rng: self.rng.clone(),
+            rules: self.rules.clone(),
+            weights: self.weights.clone(),
+            scatter_weights: self.scatter_weights.clone(),
+        }
+    }
+}
+
+impl<R: Rule> Rule for WeightedRule<R> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        scatter: bool,
+    ) -> (Point, usize) {
+        if !scatter {
+            let i = self.weights.sample(&mut self.rng);
+            return self.rules[i].next(previous, history, shape, scatter);
+        }
+
+        let i = self.scatter_weights.sample(&mut self.rng);
+        let mut res = self.rules[i].next(previous, history, shape, scatter);
+        res.0.mul_weight(self.weights.probability(i) / self.scatter_weights.probability(i));
+
+        res
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.rng.reseed(seed);
+        for rule in self.rules.iter_mut() {
+            rule.reseed(seed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alias_table() {
+        let weights = [0.01, 0.85, 0.07, 0.07, 0.0];
+        let table = AliasTable::new(&weights).unwrap();
+
+        // Each outcome is drawn from its own column, and from the columns that alias to it
+        let n = table.len() as f64;
+        for (i, weight) in weights.iter().enumerate() {
+            let p = table.keep[i] / n + (0..table.len())
+                .filter(|&j| table.alias[j] == i && j != i)
+                .map(|j| (1.0 - table.keep[j]) / n)
+                .sum::<f64>();
+            assert!((p - weight).abs() < 1e-12);
+        }
+
+        let mut rng = RuleRng::new();
+        assert!((0..1000).all(|_| table.sample(&mut rng) != 4));
+
+        assert!(AliasTable::new(&[0.0, 0.0]).is_err());
+        assert!(AliasTable::new(&[1.0, -1.0]).is_err());
+        assert!(WeightedRule::new(vec![DefaultRule::default(); 2], &[0.5, 0.5], Some(&[1.0, 0.0])).is_err());
+    }
+}
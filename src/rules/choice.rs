@@ -11,7 +11,7 @@ mod crate_macro {
             impl $name {
                 pub fn new() -> Self {
                     Self {
-                        rng: RuleRng::from_entropy(),
+                        rng: RuleRng::new(),
                     }
                 }
             }
@@ -19,7 +19,7 @@ mod crate_macro {
             impl Default for $name {
                 fn default() -> Self {
                     Self {
-                        rng: RuleRng::from_entropy(),
+                        rng: RuleRng::new(),
                     }
                 }
             }
@@ -35,7 +35,7 @@ mod crate_macro {
             impl $name {
                 pub fn new($param: $type) -> Self {
                     Self {
-                        rng: RuleRng::from_entropy(),
+                        rng: RuleRng::new(),
                         $param,
                     }
                 }
@@ -44,7 +44,7 @@ mod crate_macro {
             impl Default for $name {
                 fn default() -> Self {
                     Self {
-                        rng: RuleRng::from_entropy(),
+                        rng: RuleRng::new(),
                         $param: $default,
                     }
                 }
@@ -87,7 +87,7 @@ impl Choice for AvoidChoice {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AvoidTwoChoice {
     rng: RuleRng,
     diff: isize,
@@ -97,23 +97,13 @@ pub struct AvoidTwoChoice {
 impl AvoidTwoChoice {
     pub fn new(diff: isize, diff2: isize) -> Self {
         Self {
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             diff,
             diff2,
         }
     }
 }
 
-impl Default for AvoidTwoChoice {
-    fn default() -> Self {
-        Self {
-            rng: RuleRng::from_entropy(),
-            diff: 0,
-            diff2: 0,
-        }
-    }
-}
-
 impl Choice for AvoidTwoChoice {
     #[inline]
     fn choose_point(&mut self, history: &[usize], shape: &Shape) -> usize {
@@ -207,7 +197,7 @@ impl MatrixChoice {
         };
 
         Some(Self {
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             n_points,
             matrix
         })
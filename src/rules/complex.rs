@@ -0,0 +1,391 @@
+use super::*;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// A complex number, for the rules that treat `(x, y)` as `x + iy`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const ONE: Self = Self {re: 1.0, im: 0.0};
+
+    pub const fn new(re: f64, im: f64) -> Self {
+        Self {re, im}
+    }
+
+    #[inline]
+    pub fn norm(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// The principal square root, whose real part is positive
+    #[inline]
+    pub fn sqrt(&self) -> Self {
+        let norm = self.norm();
+        let re = ((norm + self.re) / 2.0).sqrt();
+        let im = ((norm - self.re) / 2.0).sqrt();
+
+        Self::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    #[inline]
+    pub fn is_finite(&self) -> bool {
+        self.re.is_finite() && self.im.is_finite()
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn mul(self, other: Self) -> Self {
+        Self::new(self.re * other.re - self.im * other.im, self.re * other.im + self.im * other.re)
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn div(self, other: Self) -> Self {
+        let denominator = other.re * other.re + other.im * other.im;
+
+        Self::new(
+            (self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator,
+        )
+    }
+}
+
+impl Neg for Complex {
+    type Output = Self;
+
+    #[inline]
+    fn neg(self) -> Self {
+        Self::new(-self.re, -self.im)
+    }
+}
+
+/// The Möbius transform `z ↦ (az + b) / (cz + d)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mobius {
+    pub a: Complex,
+    pub b: Complex,
+    pub c: Complex,
+    pub d: Complex,
+}
+
+impl Mobius {
+    /// Returns `ad - bc`, which is zero for degenerate transforms that send the whole plane to a single point
+    #[inline]
+    pub fn determinant(&self) -> Complex {
+        self.a * self.d - self.b * self.c
+    }
+
+    /// Applies the transform to `z`; a `z` that isn't finite is treated as the point at infinity, which is sent to `a / c`,
+    /// and the pole `-d / c` is sent to a point that isn't finite
+    #[inline]
+    pub fn apply(&self, z: Complex) -> Complex {
+        if z.is_finite() {
+            (self.a * z + self.b) / (self.c * z + self.d)
+        } else {
+            self.a / self.c
+        }
+    }
+}
+
+/// Moves the previous point towards a point of the shape by `color_ratio` in color only; shared by the complex rules,
+/// which give the point its new position themselves
+#[inline]
+fn blend_color(previous: &Point, target: &Point, color_ratio: f64) -> (f64, f64, f64) {
+    (
+        previous.r + (target.r - previous.r) * color_ratio,
+        previous.g + (target.g - previous.g) * color_ratio,
+        previous.b + (target.b - previous.b) * color_ratio,
+    )
+}
+
+/// Applies one of several Möbius transforms to the point, chosen by `choice` among the points of the shape:
+/// the point of index `i` selects the transform `i % maps.len()` and gives the new point its color.
+/// With the generators of a Kleinian group and their inverses, in the order `a, b, A, B`, and `(avoid-choice 2)`,
+/// this draws the limit set of the group.
+#[derive(Debug)]
+pub struct MobiusRule<C: Choice> {
+    choice: RuleBox<C>,
+    maps: Vec<Mobius>,
+    color_ratio: f64,
+}
+
+impl<C: Choice> MobiusRule<C> {
+    pub fn new(choice: C, maps: Vec<Mobius>, color_ratio: f64) -> Result<Self, String> {
+        if maps.is_empty() {
+            return Err(String::from("Expected at least one Möbius transform"));
+        }
+        if let Some(map) = maps.iter().find(|map| map.determinant() == Complex::default()) {
+            return Err(format!("Expected the Möbius transforms to have a nonzero determinant ad - bc, got {:?}", map));
+        }
+
+        Ok(Self {
+            choice: RuleBox::new(choice),
+            maps,
+            color_ratio,
+        })
+    }
+}
+
+impl<C: Choice> Clone for MobiusRule<C> {
+    fn clone(&self) -> Self {
+        Self {
+            choice: self.choice.clone(),
+            maps: self.maps.clone(),
+            color_ratio: self.color_ratio,
+        }
+    }
+}
+
+impl<C: Choice> Rule for MobiusRule<C> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        _scatter: bool,
+    ) -> (Point, usize) {
+        let index = self.choice.choose_point(history, shape);
+        let previous_z = Complex::new(previous.x, previous.y);
+        // The pole goes to infinity, where the point isn't drawn but keeps its orbit;
+        // a transform with c = 0 fixes infinity though, so the point would stay there forever and restarts from the origin instead
+        let mut z = self.maps[index % self.maps.len()].apply(previous_z);
+        if !z.is_finite() && !previous_z.is_finite() {
+            z = Complex::default();
+        }
+
+        (Point::new(z.re, z.im, blend_color(&previous, &shape[index], self.color_ratio)), index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.choice.reseed(seed);
+    }
+}
+
+/// Inverse iteration of `z ↦ z² + c`, which draws the Julia set of `c`: the point moves to one of the square roots of `z - c`.
+/// The point of index `i` of the shape, chosen by `choice`, gives the new point its color; if the shape has an even number of points,
+/// it also selects the root `(-1)^i sqrt(z - c)`. Otherwise both roots couldn't be equally likely, so the branch is drawn at random.
+#[derive(Debug)]
+pub struct InverseJuliaRule<C: Choice> {
+    choice: RuleBox<C>,
+    rng: RuleRng,
+    c: Complex,
+    color_ratio: f64,
+}
+
+impl<C: Choice> InverseJuliaRule<C> {
+    pub fn new(choice: C, c: Complex, color_ratio: f64) -> Self {
+        Self {
+            choice: RuleBox::new(choice),
+            rng: RuleRng::new(),
+            c,
+            color_ratio,
+        }
+    }
+}
+
+impl<C: Choice> Clone for InverseJuliaRule<C> {
+    fn clone(&self) -> Self {
+        Self {
+            choice: self.choice.clone(),
+            rng: self.rng.clone(),
+            c: self.c,
+            color_ratio: self.color_ratio,
+        }
+    }
+}
+
+impl<C: Choice> Rule for InverseJuliaRule<C> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        _scatter: bool,
+    ) -> (Point, usize) {
+        let index = self.choice.choose_point(history, shape);
+        let root = (Complex::new(previous.x, previous.y) - self.c).sqrt();
+        let negate = if shape.len().is_multiple_of(2) {
+            !index.is_multiple_of(2)
+        } else {
+            self.rng.gen()
+        };
+        let z = if negate { -root } else { root };
+
+        (Point::new(z.re, z.im, blend_color(&previous, &shape[index], self.color_ratio)), index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.rng.reseed(seed);
+        self.choice.reseed(seed);
+    }
+}
+
+/// Inverse iteration of a polynomial `p`: the point `z` moves to one of the `n` solutions of `p(w) = z`, where `n` is the degree of `p`.
+/// The solutions are sorted by argument, and the point of index `i` of the shape, chosen by `choice`, selects the solution `i % n`
+/// and gives the new point its color. The solutions are found numerically, with the Durand-Kerner method.
+#[derive(Debug)]
+pub struct PolynomialRootRule<C: Choice> {
+    choice: RuleBox<C>,
+    /// From the highest degree to the constant term, divided by the leading coefficient
+    coefficients: Vec<Complex>,
+    color_ratio: f64,
+    roots: Vec<Complex>,
+}
+
+impl<C: Choice> PolynomialRootRule<C> {
+    /// Maximum number of iterations of the Durand-Kerner method
+    const MAX_ITERATIONS: usize = 100;
+    const TOLERANCE: f64 = 1e-12;
+
+    /// Creates the rule from the coefficients of `p`, from the highest degree to the constant term
+    pub fn new(choice: C, coefficients: &[Complex], color_ratio: f64) -> Result<Self, String> {
+        let leading = coefficients.iter().position(|x| *x != Complex::default())
+            .ok_or(String::from("Expected the polynomial to have a nonzero coefficient"))?;
+        let coefficients = coefficients[leading..].iter().map(|x| *x / coefficients[leading]).collect::<Vec<_>>();
+        if coefficients.len() < 2 {
+            return Err(String::from("Expected the polynomial to be of degree at least 1"));
+        }
+
+        Ok(Self {
+            choice: RuleBox::new(choice),
+            roots: vec![Complex::default(); coefficients.len() - 1],
+            coefficients,
+            color_ratio,
+        })
+    }
+
+    /// Finds the solutions of `p(w) = z` and stores them in `self.roots`, sorted by argument
+    fn solve(&mut self, z: Complex) {
+        let degree = self.roots.len();
+        let evaluate = |w: Complex| {
+            let value = self.coefficients.iter().fold(Complex::default(), |acc, x| acc * w + *x);
+            value - z
+        };
+
+        // The roots are bounded by Cauchy's bound, and the initial guesses are spread on a spiral within it
+        let bound = 1.0 + self.coefficients[1..].iter().enumerate()
+            .map(|(i, x)| if i == degree - 1 { (*x - z).norm() } else { x.norm() })
+            .fold(0.0, f64::max);
+        let seed = Complex::new(0.4, 0.9);
+        let mut guess = Complex::new(bound, 0.0);
+        for root in self.roots.iter_mut() {
+            *root = guess;
+            guess = guess * seed;
+        }
+
+        for _ in 0..Self::MAX_ITERATIONS {
+            let mut change: f64 = 0.0;
+
+            for i in 0..degree {
+                let mut denominator = Complex::ONE;
+                for j in 0..degree {
+                    if i != j {
+                        denominator = denominator * (self.roots[i] - self.roots[j]);
+                    }
+                }
+
+                let step = evaluate(self.roots[i]) / denominator;
+                if step.is_finite() {
+                    self.roots[i] = self.roots[i] - step;
+                    change = change.max(step.norm());
+                }
+            }
+
+            if change < Self::TOLERANCE {
+                break;
+            }
+        }
+
+        self.roots.sort_by(|a, b| a.im.atan2(a.re).total_cmp(&b.im.atan2(b.re)));
+    }
+}
+
+impl<C: Choice> Clone for PolynomialRootRule<C> {
+    fn clone(&self) -> Self {
+        Self {
+            choice: self.choice.clone(),
+            coefficients: self.coefficients.clone(),
+            color_ratio: self.color_ratio,
+            roots: self.roots.clone(),
+        }
+    }
+}
+
+impl<C: Choice> Rule for PolynomialRootRule<C> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        _scatter: bool,
+    ) -> (Point, usize) {
+        let index = self.choice.choose_point(history, shape);
+        self.solve(Complex::new(previous.x, previous.y));
+        let z = self.roots[index % self.roots.len()];
+
+        (Point::new(z.re, z.im, blend_color(&previous, &shape[index], self.color_ratio)), index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.choice.reseed(seed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_complex_rules() {
+        let close = |a: Complex, b: Complex| (a - b).norm() < 1e-9;
+
+        assert!(close(Complex::new(-4.0, 0.0).sqrt(), Complex::new(0.0, 2.0)));
+        assert!(close(Complex::new(3.0, -4.0).sqrt(), Complex::new(2.0, -1.0)));
+
+        // z ↦ 1 / z
+        let inversion = Mobius {a: Complex::default(), b: Complex::ONE, c: Complex::ONE, d: Complex::default()};
+        assert!(close(inversion.apply(Complex::new(0.0, 2.0)), Complex::new(0.0, -0.5)));
+        assert!(close(inversion.apply(Complex::new(f64::INFINITY, 0.0)), Complex::default()));
+        assert!(!inversion.apply(Complex::default()).is_finite());
+
+        let degenerate = Mobius {a: Complex::ONE, b: Complex::ONE, c: Complex::ONE, d: Complex::ONE};
+        assert!(MobiusRule::new(DefaultChoice::default(), vec![inversion, degenerate], 0.5).is_err());
+
+        // The solutions of w³ - 1 = 7 are the cube roots of 8, sorted by argument
+        let mut rule = PolynomialRootRule::new(DefaultChoice::default(), &[Complex::ONE, Complex::default(), Complex::default(), -Complex::ONE], 0.5).unwrap();
+        rule.solve(Complex::new(7.0, 0.0));
+        let (sin, cos) = (2.0 * std::f64::consts::PI / 3.0).sin_cos();
+        assert!(close(rule.roots[0], Complex::new(2.0 * cos, -2.0 * sin)));
+        assert!(close(rule.roots[1], Complex::new(2.0, 0.0)));
+        assert!(close(rule.roots[2], Complex::new(2.0 * cos, 2.0 * sin)));
+
+        assert!(PolynomialRootRule::new(DefaultChoice::default(), &[Complex::default(), Complex::ONE], 0.5).is_err());
+    }
+}
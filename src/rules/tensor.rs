@@ -185,7 +185,7 @@ impl<CBig: Choice, CSmall: Choice> TensorChoice<CBig, CSmall> {
         Self {
             choice_big: RuleBox::new(choice_big),
             choice_small: RuleBox::new(choice_small),
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             jump_prob,
             jump_any,
         }
@@ -197,7 +197,7 @@ impl Default for TensorChoice<DefaultChoice, DefaultChoice> {
         Self {
             choice_big: RuleBox::new(DefaultChoice::default()),
             choice_small: RuleBox::new(DefaultChoice::default()),
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             jump_prob: 0.5,
             jump_any: false
         }
@@ -17,7 +17,7 @@ impl<R: Rule> SpiralRule<R> {
     ) -> Self {
         Self {
             rule: RuleBox::new(rule),
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             delta_low,
             delta_high,
             epsilon_low,
@@ -89,7 +89,7 @@ impl<R: Rule> DiscreteSpiralRule<R> {
     pub fn new(rule: R, (p, p_scatter): (f64, f64), delta: f64, epsilon: f64, darken: f64) -> Result<Self, rand_distr::GeoError> {
         Ok(Self {
             rule: RuleBox::new(rule),
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             distribution: rand_distr::Geometric::new(p)?,
             distribution_scatter: rand_distr::Geometric::new(p_scatter)?,
             p,
@@ -194,7 +194,7 @@ impl<C: Choice> RandAdvanceRule<C> {
     pub fn new(choice: C, zeta: f64, omega: f64, alpha: f64, color_ratio: f64) -> Self {
         Self {
             choice: RuleBox::new(choice),
-            rng: RuleRng::from_entropy(),
+            rng: RuleRng::new(),
             distribution: if omega > 0.0 {
                 RandAdvanceDistr::SkewNormal(rand_distr::SkewNormal::new(zeta, omega, alpha).unwrap())
             } else {
@@ -383,3 +383,278 @@ impl<C: Choice> Rule for AffineAdvanceRule<C> {
         self.choice.reseed(seed);
     }
 }
+
+/// Wraps the points of a rule on a torus: their coordinates are taken modulo `width × height`,
+/// around the box of that size centered on `center`.
+/// Combined with the wrap-around mode of the world and a period matching the frame, this yields seamless tiles.
+pub struct TorusRule<R: Rule> {
+    rule: RuleBox<R>,
+    width: f64,
+    height: f64,
+    center: (f64, f64),
+}
+
+impl<R: Rule> TorusRule<R> {
+    pub fn new(rule: R, width: f64, height: f64, center: (f64, f64)) -> Self {
+        Self {
+            rule: RuleBox::new(rule),
+            width,
+            height,
+            center,
+        }
+    }
+}
+
+impl<R: Rule> Clone for TorusRule<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rule: self.rule.clone(),
+            width: self.width,
+            height: self.height,
+            center: self.center,
+        }
+    }
+}
+
+impl<R: Rule> Rule for TorusRule<R> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        scatter: bool,
+    ) -> (Point, usize) {
+        let (mut next, index) = self.rule.next(previous, history, shape, scatter);
+
+        let left = self.center.0 - self.width / 2.0;
+        let top = self.center.1 - self.height / 2.0;
+        next.x = left + (next.x - left).rem_euclid(self.width);
+        next.y = top + (next.y - top).rem_euclid(self.height);
+
+        (next, index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.rule.reseed(seed);
+    }
+}
+
+/// A finite group of symmetries of the plane around the origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Symmetry {
+    /// The `n` rotations by multiples of `2π / n`
+    Cyclic(usize),
+    /// The rotations of `Cyclic(n)` and the `n` reflections across the lines at multiples of `π / n` from the x axis
+    Dihedral(usize),
+}
+
+impl Symmetry {
+    /// Creates a group from its name, either `cyclic` or `dihedral`, and its number of rotations
+    pub fn new(name: &str, n: usize) -> Result<Self, String> {
+        if n == 0 {
+            return Err(String::from("Expected a symmetry group to have at least one rotation"));
+        }
+
+        match name {
+            "cyclic" => Ok(Self::Cyclic(n)),
+            "dihedral" => Ok(Self::Dihedral(n)),
+            _ => Err(format!("Unknown symmetry group '{}', expected either cyclic or dihedral", name)),
+        }
+    }
+
+    /// Number of elements in the group
+    pub fn order(&self) -> usize {
+        match self {
+            Self::Cyclic(n) => *n,
+            Self::Dihedral(n) => 2 * n,
+        }
+    }
+
+    /// Applies the element `k` of the group to `(x, y)`; the element 0 is the identity
+    #[inline]
+    pub fn apply(&self, k: usize, (x, y): (f64, f64)) -> (f64, f64) {
+        let n = match self {
+            Self::Cyclic(n) | Self::Dihedral(n) => *n,
+        };
+        let (sin, cos) = (2.0 * std::f64::consts::PI * (k % n) as f64 / n as f64).sin_cos();
+
+        if k < n {
+            (cos * x - sin * y, sin * x + cos * y)
+        } else {
+            (cos * x + sin * y, sin * x - cos * y)
+        }
+    }
+}
+
+/// Applies a random element of a symmetry group to the points of a rule, which makes its attractor symmetric.
+/// The identity is applied with a probability of `p_scatter` during scatter steps, and the other elements are equally likely;
+/// the weight of the points is corrected so that every element of the group ends up with the same importance.
+pub struct SymmetryRule<R: Rule> {
+    rng: RuleRng,
+    rule: RuleBox<R>,
+    symmetry: Symmetry,
+    p_scatter: f64,
+}
+
+impl<R: Rule> SymmetryRule<R> {
+    /// `p_scatter` defaults to `1 / order`, in which case scatter steps draw the elements uniformly too
+    pub fn new(rule: R, symmetry: Symmetry, p_scatter: Option<f64>) -> Result<Self, String> {
+        let p = 1.0 / symmetry.order() as f64;
+        let p_scatter = p_scatter.unwrap_or(p);
+        if p_scatter <= 0.0 || p_scatter > 1.0 || (p_scatter == 1.0 && p < 1.0) {
+            return Err(format!("Expected the probability of the identity during scatter steps to be in (0, 1), got {}", p_scatter));
+        }
+
+        Ok(Self {
+            rng: RuleRng::new(),
+            rule: RuleBox::new(rule),
+            symmetry,
+            p_scatter,
+        })
+    }
+
+    /// The probability of drawing the element `k` of the group
+    fn probability(&self, k: usize, scatter: bool) -> f64 {
+        let order = self.symmetry.order();
+        let p_identity = if scatter { self.p_scatter } else { 1.0 / order as f64 };
+
+        if k == 0 {
+            p_identity
+        } else {
+            (1.0 - p_identity) / (order - 1) as f64
+        }
+    }
+
+    /// The weight of a point drawn with the element `k` during a scatter step, so that each element contributes `1 / order`
+    fn scatter_weight(&self, k: usize) -> f64 {
+        1.0 / (self.symmetry.order() as f64 * self.probability(k, true))
+    }
+}
+
+impl<R: Rule> Clone for SymmetryRule<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rng: self.rng.clone(),
+            rule: self.rule.clone(),
+            symmetry: self.symmetry,
+            p_scatter: self.p_scatter,
+        }
+    }
+}
+
+impl<R: Rule> Rule for SymmetryRule<R> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        scatter: bool,
+    ) -> (Point, usize) {
+        let order = self.symmetry.order();
+        if order == 1 {
+            return self.rule.next(previous, history, shape, scatter);
+        }
+
+        let (mut next, index) = self.rule.next(previous, history, shape, scatter);
+
+        let element = if self.rng.gen::<f64>() < self.probability(0, scatter) {
+            0
+        } else {
+            self.rng.gen_range(1..order)
+        };
+
+        (next.x, next.y) = self.symmetry.apply(element, (next.x, next.y));
+        if scatter {
+            next.mul_weight(self.scatter_weight(element));
+        }
+
+        (next, index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.rng.reseed(seed);
+        self.rule.reseed(seed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn assert_close((x, y): (f64, f64), (ex, ey): (f64, f64)) {
+        assert!((x - ex).abs() < 1e-12 && (y - ey).abs() < 1e-12, "expected ({}, {}), got ({}, {})", ex, ey, x, y);
+    }
+
+    #[test]
+    fn test_torus_rule() {
+        let shape = polygon(3);
+        let wrap = |rule: &mut TorusRule<IdentityRule>, (x, y): (f64, f64)| {
+            let (next, _) = rule.next(Point::new(x, y, (0.0, 0.0, 0.0)), &[0], &shape, false);
+            (next.x, next.y)
+        };
+
+        // The box [0, 2) × [0, 1)
+        let mut rule = TorusRule::new(IdentityRule, 2.0, 1.0, (1.0, 0.5));
+        assert_close(wrap(&mut rule, (0.5, 0.25)), (0.5, 0.25));
+        assert_close(wrap(&mut rule, (-0.5, -0.25)), (1.5, 0.75));
+        assert_close(wrap(&mut rule, (-4.0, -3.0)), (0.0, 0.0));
+        assert_close(wrap(&mut rule, (2.5, 1.25)), (0.5, 0.25));
+
+        // The box [2, 4) × [-1.5, -0.5), which doesn't contain the origin
+        let mut rule = TorusRule::new(IdentityRule, 2.0, 1.0, (3.0, -1.0));
+        assert_close(wrap(&mut rule, (0.0, 0.0)), (2.0, -1.0));
+        assert_close(wrap(&mut rule, (-0.5, -1.75)), (3.5, -0.75));
+        assert_close(wrap(&mut rule, (3.0, -1.0)), (3.0, -1.0));
+    }
+
+    #[test]
+    fn test_symmetry() {
+        let n = 5;
+        let dihedral = Symmetry::Dihedral(n);
+        assert_eq!(dihedral.order(), 2 * n);
+        assert_eq!(Symmetry::new("dihedral", n), Ok(dihedral));
+        assert!(Symmetry::new("dihedral", 0).is_err());
+
+        for k in 0..n {
+            let angle = 2.0 * PI * k as f64 / n as f64;
+            assert_close(Symmetry::Cyclic(n).apply(k, (1.0, 0.0)), (angle.cos(), angle.sin()));
+            assert_close(dihedral.apply(k, (1.0, 0.0)), (angle.cos(), angle.sin()));
+        }
+
+        // The element n + k reflects across the line at k * π / n: it fixes that line and negates its normal
+        for k in 0..n {
+            let angle = PI * k as f64 / n as f64;
+            let (sin, cos) = angle.sin_cos();
+            assert_close(dihedral.apply(n + k, (cos, sin)), (cos, sin));
+            assert_close(dihedral.apply(n + k, (-sin, cos)), (sin, -cos));
+        }
+    }
+
+    #[test]
+    fn test_symmetry_weights() {
+        for symmetry in [Symmetry::Cyclic(3), Symmetry::Dihedral(4)] {
+            for p_scatter in [None, Some(0.1), Some(0.5), Some(0.9)] {
+                let rule = SymmetryRule::new(IdentityRule, symmetry, p_scatter).unwrap();
+                let order = symmetry.order();
+
+                for scatter in [false, true] {
+                    let total = (0..order).map(|k| rule.probability(k, scatter)).sum::<f64>();
+                    assert!((total - 1.0).abs() < 1e-12);
+                }
+
+                // Once weighted, every element of the group is drawn with the same importance during scatter steps
+                let mut total = 0.0;
+                for k in 0..order {
+                    let importance = rule.probability(k, true) * rule.scatter_weight(k);
+                    assert!((importance - 1.0 / order as f64).abs() < 1e-12);
+                    total += importance;
+                }
+                assert!((total - 1.0).abs() < 1e-12);
+            }
+        }
+
+        assert!(SymmetryRule::new(IdentityRule, Symmetry::Cyclic(3), Some(0.0)).is_err());
+        assert!(SymmetryRule::new(IdentityRule, Symmetry::Cyclic(3), Some(1.0)).is_err());
+    }
+}
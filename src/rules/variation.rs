@@ -0,0 +1,213 @@
+use super::*;
+use std::f64::consts::PI;
+
+/// Added to the denominators of the variations, so that points at the origin stay finite
+const EPSILON: f64 = 1e-10;
+
+/// The nonlinear functions of the fractal flame algorithm, named and defined as in flam3.
+/// As in flam3, `r` is the distance to the origin and `θ = atan2(x, y)`, the angle measured from the y axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variation {
+    Linear,
+    Sinusoidal,
+    Spherical,
+    Swirl,
+    Horseshoe,
+    Polar,
+    Handkerchief,
+    Heart,
+    Disc,
+    Spiral,
+    Hyperbolic,
+    Diamond,
+    Ex,
+    /// Halves the angle and takes the square root of the radius, adding a half turn at random
+    Julia,
+    Bent,
+    Fisheye,
+    Exponential,
+    Power,
+    Cosine,
+    Bubble,
+    Cylinder,
+    Eyefish,
+    Tangent,
+    Cross,
+}
+
+impl Variation {
+    /// Applies the variation to `(x, y)`; only `Julia` draws from `rng`
+    #[inline]
+    pub fn apply<G: Rng>(&self, (x, y): (f64, f64), rng: &mut G) -> (f64, f64) {
+        let r2 = x * x + y * y;
+        let r = r2.sqrt();
+        let theta = x.atan2(y);
+
+        match self {
+            Self::Linear => (x, y),
+            Self::Sinusoidal => (x.sin(), y.sin()),
+            Self::Spherical => (x / (r2 + EPSILON), y / (r2 + EPSILON)),
+            Self::Swirl => {
+                let (sin, cos) = r2.sin_cos();
+                (x * sin - y * cos, x * cos + y * sin)
+            }
+            Self::Horseshoe => ((x - y) * (x + y) / (r + EPSILON), 2.0 * x * y / (r + EPSILON)),
+            Self::Polar => (theta / PI, r - 1.0),
+            Self::Handkerchief => (r * (theta + r).sin(), r * (theta - r).cos()),
+            Self::Heart => (r * (theta * r).sin(), -r * (theta * r).cos()),
+            Self::Disc => {
+                let (sin, cos) = (PI * r).sin_cos();
+                (theta / PI * sin, theta / PI * cos)
+            }
+            Self::Spiral => ((theta.cos() + r.sin()) / (r + EPSILON), (theta.sin() - r.cos()) / (r + EPSILON)),
+            Self::Hyperbolic => (theta.sin() / (r + EPSILON), r * theta.cos()),
+            Self::Diamond => (theta.sin() * r.cos(), theta.cos() * r.sin()),
+            Self::Ex => {
+                let p0 = (theta + r).sin().powi(3);
+                let p1 = (theta - r).cos().powi(3);
+                (r * (p0 + p1), r * (p0 - p1))
+            }
+            Self::Julia => {
+                let omega = if rng.gen() { PI } else { 0.0 };
+                let (sin, cos) = (theta / 2.0 + omega).sin_cos();
+                (r.sqrt() * cos, r.sqrt() * sin)
+            }
+            Self::Bent => (
+                if x < 0.0 { 2.0 * x } else { x },
+                if y < 0.0 { y / 2.0 } else { y },
+            ),
+            Self::Fisheye => (2.0 / (r + 1.0) * y, 2.0 / (r + 1.0) * x),
+            Self::Exponential => {
+                let (sin, cos) = (PI * y).sin_cos();
+                ((x - 1.0).exp() * cos, (x - 1.0).exp() * sin)
+            }
+            Self::Power => {
+                let (sin, cos) = (x / (r + EPSILON), y / (r + EPSILON));
+                (r.powf(sin) * cos, r.powf(sin) * sin)
+            }
+            Self::Cosine => ((PI * x).cos() * y.cosh(), -(PI * x).sin() * y.sinh()),
+            Self::Bubble => (4.0 / (r2 + 4.0) * x, 4.0 / (r2 + 4.0) * y),
+            Self::Cylinder => (x.sin(), y),
+            Self::Eyefish => (2.0 / (r + 1.0) * x, 2.0 / (r + 1.0) * y),
+            Self::Tangent => (x.sin() / y.cos(), y.tan()),
+            Self::Cross => {
+                let factor = 1.0 / ((x * x - y * y).abs() + EPSILON);
+                (factor * x, factor * y)
+            }
+        }
+    }
+}
+
+/// Parses the name of a variation, in lowercase
+impl std::str::FromStr for Variation {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        Ok(match raw {
+            "linear" => Self::Linear,
+            "sinusoidal" => Self::Sinusoidal,
+            "spherical" => Self::Spherical,
+            "swirl" => Self::Swirl,
+            "horseshoe" => Self::Horseshoe,
+            "polar" => Self::Polar,
+            "handkerchief" => Self::Handkerchief,
+            "heart" => Self::Heart,
+            "disc" => Self::Disc,
+            "spiral" => Self::Spiral,
+            "hyperbolic" => Self::Hyperbolic,
+            "diamond" => Self::Diamond,
+            "ex" => Self::Ex,
+            "julia" => Self::Julia,
+            "bent" => Self::Bent,
+            "fisheye" => Self::Fisheye,
+            "exponential" => Self::Exponential,
+            "power" => Self::Power,
+            "cosine" => Self::Cosine,
+            "bubble" => Self::Bubble,
+            "cylinder" => Self::Cylinder,
+            "eyefish" => Self::Eyefish,
+            "tangent" => Self::Tangent,
+            "cross" => Self::Cross,
+            _ => return Err(format!("Unknown variation '{}'", raw)),
+        })
+    }
+}
+
+/// Moves the points of a rule by a weighted sum of variations, like the transforms of a fractal flame:
+/// `(x, y) ↦ Σ weight * variation(x, y)`. The colors are left untouched.
+pub struct VariationRule<R: Rule> {
+    rule: RuleBox<R>,
+    rng: RuleRng,
+    variations: Vec<(Variation, f64)>,
+}
+
+impl<R: Rule> VariationRule<R> {
+    pub fn new(rule: R, variations: Vec<(Variation, f64)>) -> Self {
+        Self {
+            rule: RuleBox::new(rule),
+            rng: RuleRng::new(),
+            variations,
+        }
+    }
+}
+
+impl<R: Rule> Clone for VariationRule<R> {
+    fn clone(&self) -> Self {
+        Self {
+            rule: self.rule.clone(),
+            rng: self.rng.clone(),
+            variations: self.variations.clone(),
+        }
+    }
+}
+
+impl<R: Rule> Rule for VariationRule<R> {
+    fn next(
+        &mut self,
+        previous: Point,
+        history: &[usize],
+        shape: &Shape,
+        scatter: bool,
+    ) -> (Point, usize) {
+        let (mut next, index) = self.rule.next(previous, history, shape, scatter);
+
+        let (mut x, mut y) = (0.0, 0.0);
+        for (variation, weight) in self.variations.iter() {
+            let (dx, dy) = variation.apply((next.x, next.y), &mut self.rng);
+            x += weight * dx;
+            y += weight * dy;
+        }
+        next.x = x;
+        next.y = y;
+
+        (next, index)
+    }
+
+    fn reseed(&mut self, seed: &[u8; 32]) {
+        self.rng.reseed(seed);
+        self.rule.reseed(seed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variations() {
+        let mut rng = RuleRng::new();
+        let close = |(x, y): (f64, f64), (ex, ey): (f64, f64)| (x - ex).abs() < 1e-9 && (y - ey).abs() < 1e-9;
+
+        assert!(close(Variation::Spherical.apply((2.0, 0.0), &mut rng), (0.5, 0.0)));
+        // The angle from the y axis is halved, to either π / 2 or -π / 2
+        let (x, y) = Variation::Julia.apply((0.0, -4.0), &mut rng);
+        assert!(close((x, y.abs()), (0.0, 2.0)));
+        assert!(close(Variation::Bent.apply((-1.0, -1.0), &mut rng), (-2.0, -0.5)));
+
+        let (x, y) = Variation::Spherical.apply((0.0, 0.0), &mut rng);
+        assert!(x.is_finite() && y.is_finite());
+
+        assert_eq!("handkerchief".parse::<Variation>(), Ok(Variation::Handkerchief));
+        assert!("swirly".parse::<Variation>().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use super::tonemap::Background;
+use super::world::World;
+
+/// How a layer is composited over the layers below it; colors are blended in linear sRGB, premultiplied by their opacity
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Blend {
+    /// Sums the light of the layers, which is how two renders of the same scene would add up
+    Add,
+    /// Like `Add`, but bright areas saturate smoothly instead of clipping
+    Screen,
+    /// Paints the layer over the ones below it, hiding them where it is opaque
+    #[default]
+    Over,
+}
+
+impl Blend {
+    /// Composites the premultiplied color `[r, g, b, a]` of a layer over `dst`, in place
+    #[inline]
+    pub fn apply(&self, dst: &mut [f64; 4], src: [f64; 4]) {
+        match self {
+            Self::Add => {
+                for i in 0..3 {
+                    dst[i] += src[i];
+                }
+                dst[3] = (dst[3] + src[3]).min(1.0);
+            }
+            Self::Screen => {
+                for i in 0..4 {
+                    dst[i] += src[i] - src[i] * dst[i];
+                }
+            }
+            Self::Over => {
+                for i in 0..4 {
+                    dst[i] = src[i] + dst[i] * (1.0 - src[3]);
+                }
+            }
+        }
+    }
+}
+
+/// Parses either `add`, `screen` or `over`
+impl std::str::FromStr for Blend {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw {
+            "add" => Ok(Self::Add),
+            "screen" => Ok(Self::Screen),
+            "over" => Ok(Self::Over),
+            _ => Err(format!("Unknown blend mode '{}', expected one of add, screen or over", raw)),
+        }
+    }
+}
+
+/// One of the renders making up a layered scene
+pub struct Layer {
+    /// Should draw over a transparent background, which the scene replaces with its own
+    pub world: World,
+    pub blend: Blend,
+}
+
+/// What gets rendered: either a single world, or several layers that each have their own accumulation buffer
+pub enum Scene {
+    Single(Box<World>),
+    /// The layers are composited from the bottom up, then blended over `background`
+    Layered {
+        layers: Vec<Layer>,
+        background: Background,
+    },
+}
+
+impl Scene {
+    /// Returns the world of a scene that isn't layered
+    pub fn single(&self) -> Option<&World> {
+        match self {
+            Self::Single(world) => Some(world),
+            Self::Layered {..} => None,
+        }
+    }
+
+    pub fn single_mut(&mut self) -> Option<&mut World> {
+        match self {
+            Self::Single(world) => Some(world),
+            Self::Layered {..} => None,
+        }
+    }
+
+    fn worlds(&self) -> Box<dyn Iterator<Item = &World> + '_> {
+        match self {
+            Self::Single(world) => Box::new(std::iter::once(&**world)),
+            Self::Layered {layers, ..} => Box::new(layers.iter().map(|layer| &layer.world)),
+        }
+    }
+
+    fn worlds_mut(&mut self) -> Box<dyn Iterator<Item = &mut World> + '_> {
+        match self {
+            Self::Single(world) => Box::new(std::iter::once(&mut **world)),
+            Self::Layered {layers, ..} => Box::new(layers.iter_mut().map(|layer| &mut layer.world)),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.worlds().next().map(|world| world.width()).unwrap_or(0)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.worlds().next().map(|world| world.height()).unwrap_or(0)
+    }
+
+    pub fn stop(&mut self) {
+        self.worlds_mut().for_each(|world| world.stop());
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.worlds_mut().for_each(|world| world.resize(width, height));
+    }
+
+    /// Returns the total number of steps of the layers
+    pub fn steps(&self) -> usize {
+        self.worlds().map(|world| world.steps()).sum()
+    }
+
+    /// Returns the noise of the noisiest layer, if the `sigma` feature is enabled
+    pub fn noise(&self) -> Option<f64> {
+        self.worlds().filter_map(|world| world.noise()).reduce(f64::max)
+    }
+
+    /// Returns true once every layer converged
+    pub fn converged(&self) -> bool {
+        self.worlds().all(|world| world.converged())
+    }
+
+    pub fn draw(&self, frame: &mut [u8]) {
+        let (layers, background) = match self {
+            Self::Single(world) => return world.draw(frame),
+            Self::Layered {layers, background} => (layers, background),
+        };
+
+        // The layers are composited at full precision, and only quantized once blended over the background
+        let mut composite = vec![[0.0; 4]; frame.len() / 4];
+        let mut buffer = vec![[0.0; 4]; frame.len() / 4];
+
+        for layer in layers {
+            layer.world.draw_linear(&mut buffer);
+
+            for (dst, src) in composite.iter_mut().zip(buffer.iter()) {
+                layer.blend.apply(dst, *src);
+            }
+        }
+
+        for (pixel, [r, g, b, a]) in frame.chunks_exact_mut(4).zip(composite) {
+            if a > 0.0 {
+                pixel.copy_from_slice(&background.blend((r / a, g / a, b / a), a));
+            } else {
+                pixel.copy_from_slice(&background.empty());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blend() {
+        let src = [0.25, 0.0, 0.5, 0.5];
+
+        let mut dst = [0.0; 4];
+        Blend::Over.apply(&mut dst, src);
+        assert_eq!(dst, src);
+        Blend::Over.apply(&mut dst, [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(dst, [1.0; 4]);
+
+        let mut dst = [0.5, 0.5, 0.0, 1.0];
+        Blend::Add.apply(&mut dst, src);
+        assert_eq!(dst, [0.75, 0.5, 0.5, 1.0]);
+
+        // Screening over black leaves the layer unchanged, and never exceeds white
+        let mut dst = [0.0; 4];
+        Blend::Screen.apply(&mut dst, src);
+        assert_eq!(dst, src);
+        Blend::Screen.apply(&mut dst, [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(dst, [1.0; 4]);
+
+        assert_eq!("screen".parse::<Blend>(), Ok(Blend::Screen));
+        assert!("multiply".parse::<Blend>().is_err());
+    }
+}
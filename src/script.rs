@@ -1,5 +1,12 @@
 use super::rules::*;
 use super::shape::{Shape, Point};
+use super::color::{srgb_to_linear, ColorSpace};
+use super::camera::Camera;
+use super::filter::DensityEstimation;
+use super::fit::Fit;
+use super::splat::Splat;
+use super::scene::Blend;
+use super::tonemap::{Background, Gain, ToneMap};
 
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -14,6 +21,43 @@ thread_local! {
     static NONCE: RefCell<usize> = RefCell::new(0);
 }
 
+/// The values defined by an input script; each of them is `None` if the script didn't set it
+pub struct ScriptResult {
+    pub rule: Option<BoxedRule>,
+    /// Set by FINAL, a rule applied to the points before they are drawn without changing their orbit,
+    /// like `(variation-rule (identity-rule) '((swirl 1)))`
+    pub final_rule: Option<BoxedRule>,
+    pub shape: Option<Shape>,
+    pub scale: Option<f64>,
+    /// Set if SCALE is `'auto` or `'(auto mode param...)`, in which case the view is fitted to the attractor
+    pub fit: Option<Fit>,
+    pub center: Option<(f64, f64)>,
+    pub camera: Option<Camera>,
+    pub seed: Option<u64>,
+    pub tone_map: Option<ToneMap>,
+    pub gain: Option<Gain>,
+    pub splat: Option<Splat>,
+    pub density_estimation: Option<DensityEstimation>,
+    pub background: Option<Background>,
+    pub color_space: Option<ColorSpace>,
+    /// Set by WRAP, which makes the frame wrap around its edges if truthy
+    pub wrap: Option<bool>,
+    /// Set by LAYERS, in which case the script doesn't need to return a rule
+    pub layers: Option<Vec<ScriptLayer>>,
+}
+
+/// A layer of LAYERS, given as `(list rule shape settings)`, where `settings` is an optional list like
+/// `'((steps 5000000) (gain (auto 0.9)) (tone-map (log 2 1.5)) (blend screen))`; the unset settings are those of the scene
+pub struct ScriptLayer {
+    pub rule: BoxedRule,
+    pub shape: Shape,
+    /// Number of steps after which the layer stops
+    pub steps: Option<usize>,
+    pub gain: Option<Gain>,
+    pub tone_map: Option<ToneMap>,
+    pub blend: Blend,
+}
+
 fn expect_arg(args: &Vec<Value>, argno: usize) -> Result<&Value, RuntimeError> {
     match args.get(argno) {
         Some(x) => Ok(x),
@@ -193,6 +237,17 @@ fn advance_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, Runt
     Ok(Value::Symbol(name))
 }
 
+fn identity_rule(_env: Rc<RefCell<Env>>, _args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let name = format!("IdentityRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(IdentityRule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
 fn spiral_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
     let rule = get_rule(as_symbol(expect_arg(args, 0)?)?)?;
 
@@ -258,6 +313,82 @@ fn darken_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, Runti
     Ok(Value::Symbol(name))
 }
 
+/// `(torus-rule rule width height [cx cy])`: for a seamless tile, the period should match the frame,
+/// which spans `2 SCALE` along its smallest side
+fn torus_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let rule = get_rule(as_symbol(expect_arg(args, 0)?)?)?;
+
+    let width = as_number(expect_arg(args, 1)?)?;
+    let height = as_number(expect_arg(args, 2)?)?;
+    if width <= 0.0 || height <= 0.0 {
+        return Err(RuntimeError::new(format!("Expected the period of torus-rule to be positive, got {} and {}", width, height)));
+    }
+    let cx = as_number(args.get(3).unwrap_or(&Value::Float(0.0)))?;
+    let cy = as_number(args.get(4).unwrap_or(&Value::Float(0.0)))?;
+
+    let rule = TorusRule::new(rule, width, height, (cx, cy));
+
+    let name = format!("TorusRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
+/// `(variation-rule rule '((swirl 0.5) (spherical 0.5)))`, where a variation without a weight, like `'(julia)`, has a weight of 1
+fn variation_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let rule = get_rule(as_symbol(expect_arg(args, 0)?)?)?;
+
+    let variations = match expect_arg(args, 1)? {
+        Value::List(list) => list.into_iter().map(|variation| {
+            let (name, params) = extract_named(&variation, "a variation")?;
+            let variation = name.parse::<Variation>().map_err(RuntimeError::new)?;
+
+            match params[..] {
+                [] => Ok((variation, 1.0)),
+                [weight] => Ok((variation, weight)),
+                _ => Err(RuntimeError::new(format!("Expected variation {} to have at most one weight", name))),
+            }
+        }).collect::<Result<Vec<_>, _>>()?,
+        y => return Err(RuntimeError::new(format!("Expected a list of variations, got {:?}", y))),
+    };
+
+    let rule = VariationRule::new(rule, variations);
+
+    let name = format!("VariationRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
+/// `(symmetry-rule rule 'dihedral n [p-scatter])`, or `'cyclic` for rotations only
+fn symmetry_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let rule = get_rule(as_symbol(expect_arg(args, 0)?)?)?;
+
+    let group = as_symbol(expect_arg(args, 1)?)?;
+    let n = as_int(expect_arg(args, 2)?)?;
+    let p_scatter = args.get(3).map(as_number).transpose()?;
+
+    let symmetry = Symmetry::new(&group, n.max(0) as usize).map_err(RuntimeError::new)?;
+    let rule = SymmetryRule::new(rule, symmetry, p_scatter).map_err(RuntimeError::new)?;
+
+    let name = format!("SymmetryRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
 fn or_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
     let p = as_number(args.get(0).unwrap_or(&Value::Float(0.5)))?;
     let p_scatter = as_number(args.get(3).unwrap_or(&Value::Float(0.5)))?;
@@ -277,6 +408,40 @@ fn or_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeEr
     Ok(Value::Symbol(name))
 }
 
+/// `(weighted-rule (list (list rule weight [scatter-weight]) ...))`
+fn weighted_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let entries = match expect_arg(args, 0)? {
+        Value::List(list) => list.into_iter().collect::<Vec<_>>(),
+        x => return Err(RuntimeError::new(format!("Expected a list of (rule weight) pairs, got {:?}", x))),
+    };
+
+    let mut rules = Vec::with_capacity(entries.len());
+    let mut weights = Vec::with_capacity(entries.len());
+    let mut scatter_weights = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let entry = match entry {
+            Value::List(list) => list.into_iter().collect::<Vec<_>>(),
+            x => return Err(RuntimeError::new(format!("Expected a (rule weight) pair, got {:?}", x))),
+        };
+
+        rules.push(get_rule(as_symbol(expect_arg(&entry, 0)?)?)?);
+        let weight = as_number(expect_arg(&entry, 1)?)?;
+        weights.push(weight);
+        scatter_weights.push(entry.get(2).map(as_number).transpose()?.unwrap_or(weight));
+    }
+
+    let rule = WeightedRule::new(rules, &weights, Some(&scatter_weights)).map_err(RuntimeError::new)?;
+
+    let name = format!("WeightedRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
 fn tensor_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
     let choice = get_choice(as_symbol(expect_arg(args, 0)?)?)?;
     let move_ratio = as_number(args.get(1).unwrap_or(&Value::Float(0.5)))?;
@@ -384,6 +549,92 @@ fn affine_advance_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Valu
     Ok(Value::Symbol(name))
 }
 
+/// Extracts a complex number from either a real number or a list `(re im)`
+fn extract_complex(value: &Value) -> Result<Complex, RuntimeError> {
+    match value {
+        Value::List(list) => {
+            let parts = list.into_iter().map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?;
+            match parts[..] {
+                [re, im] => Ok(Complex::new(re, im)),
+                _ => Err(RuntimeError::new(format!("Expected a complex number to be a list (re im), got {}", value))),
+            }
+        }
+        x => Ok(Complex::new(as_number(x)?, 0.0)),
+    }
+}
+
+/// `(mobius-rule choice '((a b c d) ...) [color-ratio])`, where each coefficient is a number or a list `(re im)`
+fn mobius_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let choice = get_choice(as_symbol(expect_arg(args, 0)?)?)?;
+    let list = expect_arg(args, 1)?.as_list().ok_or(RuntimeError::new(
+        format!("Expected list of Möbius transforms, got {:?}", args[1])
+    ))?;
+    let color_ratio = as_number(args.get(2).unwrap_or(&Value::Float(0.5)))?;
+
+    let mut maps = Vec::new();
+    for map in list.into_iter() {
+        let coefficients = match &map {
+            Value::List(coefficients) => coefficients.into_iter().map(|x| extract_complex(&x)).collect::<Result<Vec<_>, _>>()?,
+            y => return Err(RuntimeError::new(format!("Expected a Möbius transform to be a list (a b c d), got {:?}", y))),
+        };
+        match coefficients[..] {
+            [a, b, c, d] => maps.push(Mobius {a, b, c, d}),
+            _ => return Err(RuntimeError::new(format!("Expected a Möbius transform to have 4 coefficients, got {}", coefficients.len()))),
+        }
+    }
+
+    let rule = MobiusRule::new(choice, maps, color_ratio).map_err(RuntimeError::new)?;
+
+    let name = format!("MobiusRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
+/// `(inverse-julia-rule choice c [color-ratio])`, where `c` is a number or a list `(re im)`
+fn inverse_julia_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let choice = get_choice(as_symbol(expect_arg(args, 0)?)?)?;
+    let c = extract_complex(expect_arg(args, 1)?)?;
+    let color_ratio = as_number(args.get(2).unwrap_or(&Value::Float(0.5)))?;
+
+    let rule = InverseJuliaRule::new(choice, c, color_ratio);
+
+    let name = format!("InverseJuliaRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
+/// `(polynomial-root-rule choice '(coefficient ...) [color-ratio])`, with the coefficients from the highest degree to the constant term
+fn polynomial_root_rule(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let choice = get_choice(as_symbol(expect_arg(args, 0)?)?)?;
+    let list = expect_arg(args, 1)?.as_list().ok_or(RuntimeError::new(
+        format!("Expected list of coefficients, got {:?}", args[1])
+    ))?;
+    let color_ratio = as_number(args.get(2).unwrap_or(&Value::Float(0.5)))?;
+
+    let coefficients = list.into_iter().map(|x| extract_complex(&x)).collect::<Result<Vec<_>, _>>()?;
+
+    let rule = PolynomialRootRule::new(choice, &coefficients, color_ratio).map_err(RuntimeError::new)?;
+
+    let name = format!("PolynomialRootRule {}", next_index());
+
+    RULES.with(|r| r.borrow_mut().insert(
+        name.clone(),
+        BoxedRule::new(rule)
+    ));
+
+    Ok(Value::Symbol(name))
+}
+
 fn float(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
     match args.get(0) {
         Some(Value::Float(x)) => Ok(Value::Float(*x)),
@@ -414,6 +665,17 @@ fn pow(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError>
     Ok(Value::Float((left as f32).powf(right as f32)))
 }
 
+/// Converts an sRGB color with components between 0 and 255 to a list of linear components
+fn srgb(_env: Rc<RefCell<Env>>, args: &Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut res = Vec::with_capacity(3);
+    for argno in 0..3 {
+        let x = as_number(expect_arg(args, argno)?)?;
+        res.push(Value::Float(srgb_to_linear(x / 255.0) as f32));
+    }
+
+    Ok(Value::List(res.into_iter().collect()))
+}
+
 fn populate_env(env: &mut Env) {
     env.entries.insert(
         String::from("float"),
@@ -422,6 +684,7 @@ fn populate_env(env: &mut Env) {
 
     env.entries.insert(String::from("%"), Value::NativeFunc(modulo));
     env.entries.insert(String::from("pow"), Value::NativeFunc(pow));
+    env.entries.insert(String::from("srgb"), Value::NativeFunc(srgb));
 
     crate_macro::lisp_mathfun!(env, sqrt);
     crate_macro::lisp_mathfun!(env, exp);
@@ -437,6 +700,11 @@ fn populate_env(env: &mut Env) {
         Value::NativeFunc(advance_rule)
     );
 
+    env.entries.insert(
+        String::from("identity-rule"),
+        Value::NativeFunc(identity_rule)
+    );
+
     env.entries.insert(
         String::from("spiral-rule"),
         Value::NativeFunc(spiral_rule)
@@ -452,11 +720,31 @@ fn populate_env(env: &mut Env) {
         Value::NativeFunc(or_rule)
     );
 
+    env.entries.insert(
+        String::from("weighted-rule"),
+        Value::NativeFunc(weighted_rule)
+    );
+
     env.entries.insert(
         String::from("darken-rule"),
         Value::NativeFunc(darken_rule)
     );
 
+    env.entries.insert(
+        String::from("torus-rule"),
+        Value::NativeFunc(torus_rule)
+    );
+
+    env.entries.insert(
+        String::from("variation-rule"),
+        Value::NativeFunc(variation_rule)
+    );
+
+    env.entries.insert(
+        String::from("symmetry-rule"),
+        Value::NativeFunc(symmetry_rule)
+    );
+
     env.entries.insert(
         String::from("tensor-rule"),
         Value::NativeFunc(tensor_rule)
@@ -482,6 +770,21 @@ fn populate_env(env: &mut Env) {
         Value::NativeFunc(merge_rule)
     );
 
+    env.entries.insert(
+        String::from("mobius-rule"),
+        Value::NativeFunc(mobius_rule)
+    );
+
+    env.entries.insert(
+        String::from("inverse-julia-rule"),
+        Value::NativeFunc(inverse_julia_rule)
+    );
+
+    env.entries.insert(
+        String::from("polynomial-root-rule"),
+        Value::NativeFunc(polynomial_root_rule)
+    );
+
     env.entries.insert(
         String::from("choice"),
         Value::NativeFunc(choice)
@@ -518,8 +821,14 @@ fn populate_env(env: &mut Env) {
     );
 }
 
-fn extract_shape(value: &Value) -> Result<Shape, RuntimeError> {
-    use rand::Rng;
+/// Extracts the shape from `value`; points without a color get a random one, drawn from `seed` if set
+fn extract_shape(value: &Value, seed: Option<u64>) -> Result<Shape, RuntimeError> {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = match seed {
+        Some(seed) => rand_xoshiro::Xoshiro256Plus::seed_from_u64(seed),
+        None => rand_xoshiro::Xoshiro256Plus::from_entropy(),
+    };
 
     let mut res = Vec::new();
     if let Value::List(list) = value {
@@ -537,7 +846,6 @@ fn extract_shape(value: &Value) -> Result<Shape, RuntimeError> {
                 }
 
                 let (x, y, r, g, b) = if numbers.len() == 2 {
-                    let mut rng = rand::thread_rng();
                     (numbers[0], numbers[1], rng.gen(), rng.gen(), rng.gen())
                 } else if numbers.len() == 5 {
                     (numbers[0], numbers[1], numbers[2], numbers[3], numbers[4])
@@ -557,6 +865,195 @@ fn extract_shape(value: &Value) -> Result<Shape, RuntimeError> {
     }
 }
 
+fn extract_seed(value: &Value) -> Result<u64, RuntimeError> {
+    match value {
+        Value::Int(x) => Ok(*x as u64),
+        Value::String(x) => x.parse::<u64>().map_err(|e| RuntimeError::new(format!("Invalid SEED: {:?}", e))),
+        y => Err(RuntimeError::new(format!("Expected SEED to be an integer or a string, got {:?}", y))),
+    }
+}
+
+/// Extracts a value of the form `'name` or `'(name param...)`, returning the name and the parameters
+fn extract_named(value: &Value, variable: &str) -> Result<(String, Vec<f64>), RuntimeError> {
+    match value {
+        Value::Symbol(name) => Ok((name.clone(), Vec::new())),
+        Value::List(list) => {
+            let mut iter = list.into_iter();
+            let name = iter.next().ok_or(RuntimeError::new(format!("Expected {} to be a non-empty list", variable)))?;
+            let params = iter.map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?;
+
+            Ok((as_symbol(&name)?, params))
+        }
+        y => Err(RuntimeError::new(format!("Expected {} to be a symbol or a list, got {:?}", variable, y))),
+    }
+}
+
+/// Extracts the tone map from either a symbol, like `'hist`, or a list, like `'(log 2.0 1.5)`
+fn extract_tone_map(value: &Value) -> Result<ToneMap, RuntimeError> {
+    let (name, params) = extract_named(value, "TONE_MAP")?;
+
+    ToneMap::new(&name, &params).map_err(RuntimeError::new)
+}
+
+/// Extracts either the maximum radius or a list `(max_radius min_radius curve)`, where the last two are optional
+fn extract_density_estimation(value: &Value) -> Result<DensityEstimation, RuntimeError> {
+    let params = match value {
+        Value::List(list) => list.into_iter().map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?,
+        x => vec![as_number(x)?],
+    };
+    let default = DensityEstimation::default();
+
+    let res = match params[..] {
+        [max_radius] => DensityEstimation::new(max_radius, default.min_radius, default.curve),
+        [max_radius, min_radius] => DensityEstimation::new(max_radius, min_radius, default.curve),
+        [max_radius, min_radius, curve] => DensityEstimation::new(max_radius, min_radius, curve),
+        _ => Err(String::from("Expected DENSITY_ESTIMATION to have between one and three parameters")),
+    };
+
+    res.map_err(RuntimeError::new)
+}
+
+/// Extracts either `'transparent` or a linear color, as returned by `srgb`
+fn extract_background(value: &Value) -> Result<Background, RuntimeError> {
+    match value {
+        Value::Symbol(name) if name == "transparent" => Ok(Background::Transparent),
+        Value::List(list) => {
+            let components = list.into_iter().map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?;
+
+            match components[..] {
+                [r, g, b] => Ok(Background::Color(r, g, b)),
+                _ => Err(RuntimeError::new(format!("Expected BACKGROUND to have three components, got {}", components.len()))),
+            }
+        }
+        y => Err(RuntimeError::new(format!("Expected BACKGROUND to be a color or 'transparent, got {:?}", y))),
+    }
+}
+
+fn extract_color_space(value: &Value) -> Result<ColorSpace, RuntimeError> {
+    as_symbol(value)?.parse::<ColorSpace>().map_err(RuntimeError::new)
+}
+
+/// Extracts the camera from a list of settings, like `'((rotation 30) (stretch 1 1.5) flip-x)`
+fn extract_camera(value: &Value) -> Result<Camera, RuntimeError> {
+    let settings = match value {
+        Value::List(list) => list.into_iter().map(|x| extract_named(&x, "CAMERA")).collect::<Result<Vec<_>, _>>()?,
+        y => return Err(RuntimeError::new(format!("Expected CAMERA to be a list of settings, got {:?}", y))),
+    };
+
+    Camera::new(&settings).map_err(RuntimeError::new)
+}
+
+/// Extracts the fitting mode from either `'auto` or a list like `'(auto percentile 0.001 0.05)`
+fn extract_fit(value: &Value) -> Result<Fit, RuntimeError> {
+    let (name, params) = match value {
+        Value::List(list) => {
+            let mut iter = list.into_iter();
+            match iter.next() {
+                Some(Value::Symbol(name)) if name == "auto" => {}
+                _ => return Err(RuntimeError::new(format!("Expected SCALE to be a number or a list starting with 'auto, got {}", value))),
+            }
+            match iter.next() {
+                Some(mode) => (as_symbol(&mode)?, iter.map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?),
+                None => return Ok(Fit::default()),
+            }
+        }
+        Value::Symbol(name) if name == "auto" => return Ok(Fit::default()),
+        y => return Err(RuntimeError::new(format!("Expected SCALE to be a number or 'auto, got {:?}", y))),
+    };
+
+    Fit::new(&name, &params).map_err(RuntimeError::new)
+}
+
+fn extract_splat(value: &Value) -> Result<Splat, RuntimeError> {
+    let (name, params) = extract_named(value, "SPLAT")?;
+
+    Splat::new(&name, &params).map_err(RuntimeError::new)
+}
+
+/// Extracts the gain from either a number, `'auto` or a list like `'(auto 0.9 0.8)`
+fn extract_gain(value: &Value) -> Result<Gain, RuntimeError> {
+    match value {
+        Value::Float(_) | Value::Int(_) => Ok(Gain::Fixed(as_number(value)?)),
+        Value::Symbol(name) if name == "auto" => Ok(Gain::default_auto()),
+        Value::List(list) => {
+            let mut iter = list.into_iter();
+            match iter.next() {
+                Some(Value::Symbol(name)) if name == "auto" => {}
+                _ => return Err(RuntimeError::new(format!("Expected GAIN to be a list starting with 'auto, got {}", value))),
+            }
+            let params = iter.map(|x| as_number(&x)).collect::<Result<Vec<_>, _>>()?;
+
+            match params[..] {
+                [] => Ok(Gain::default_auto()),
+                [percentile] => Gain::auto(percentile, 0.9).map_err(RuntimeError::new),
+                [percentile, target] => Gain::auto(percentile, target).map_err(RuntimeError::new),
+                _ => Err(RuntimeError::new("Expected at most two parameters for auto gain")),
+            }
+        }
+        y => Err(RuntimeError::new(format!("Expected GAIN to be a number or 'auto, got {:?}", y))),
+    }
+}
+
+/// Extracts the list of layers, see `ScriptLayer`
+fn extract_layers(value: &Value, seed: Option<u64>) -> Result<Vec<ScriptLayer>, RuntimeError> {
+    let list = match value {
+        Value::List(list) if list.into_iter().next().is_some() => list,
+        y => return Err(RuntimeError::new(format!("Expected LAYERS to be a non-empty list, got {:?}", y))),
+    };
+
+    let mut res = Vec::new();
+    for layer in list {
+        let items = match &layer {
+            Value::List(items) => items.into_iter().collect::<Vec<_>>(),
+            y => return Err(RuntimeError::new(format!("Expected a layer to be a list (rule shape [settings]), got {:?}", y))),
+        };
+        let (rule, shape, settings) = match &items[..] {
+            [rule, shape] => (rule, shape, None),
+            [rule, shape, settings] => (rule, shape, Some(settings)),
+            _ => return Err(RuntimeError::new(format!("Expected a layer to have a rule, a shape and optional settings, got {} items", items.len()))),
+        };
+
+        let mut res_layer = ScriptLayer {
+            rule: get_rule(as_symbol(rule)?)?,
+            shape: extract_shape(shape, seed)?,
+            steps: None,
+            gain: None,
+            tone_map: None,
+            blend: Blend::default(),
+        };
+
+        let settings = match settings {
+            Some(Value::List(settings)) => settings.into_iter().collect::<Vec<_>>(),
+            Some(y) => return Err(RuntimeError::new(format!("Expected the settings of a layer to be a list, got {:?}", y))),
+            None => Vec::new(),
+        };
+        for setting in settings {
+            let (name, value) = match &setting {
+                Value::List(setting) => {
+                    let mut iter = setting.into_iter();
+                    match (iter.next(), iter.next(), iter.next()) {
+                        (Some(name), Some(value), None) => (as_symbol(&name)?, value),
+                        _ => return Err(RuntimeError::new(format!("Expected a layer setting to be a pair (name value), got {}", setting))),
+                    }
+                }
+                y => return Err(RuntimeError::new(format!("Expected a layer setting to be a pair (name value), got {:?}", y))),
+            };
+
+            match name.as_str() {
+                "steps" => res_layer.steps = Some(as_number(&value)? as usize),
+                "gain" => res_layer.gain = Some(extract_gain(&value)?),
+                "tone-map" => res_layer.tone_map = Some(extract_tone_map(&value)?),
+                "blend" => res_layer.blend = as_symbol(&value)?.parse::<Blend>().map_err(RuntimeError::new)?,
+                _ => return Err(RuntimeError::new(format!("Unknown layer setting '{}', expected one of steps, gain, tone-map or blend", name))),
+            }
+        }
+
+        res.push(res_layer);
+    }
+
+    Ok(res)
+}
+
 fn eval_prelude(env: Rc<RefCell<Env>>) -> Result<(), RuntimeError> {
     let mut ast = Vec::new();
 
@@ -568,7 +1065,11 @@ fn eval_prelude(env: Rc<RefCell<Env>>) -> Result<(), RuntimeError> {
     Ok(())
 }
 
-pub fn eval_rule(raw: &str) -> Result<(Option<BoxedRule>, Option<Shape>, Option<f64>, Option<(f64, f64)>), RuntimeError> {
+/// Evaluates the script `raw`; `seed` is used as the default value for `SEED`
+pub fn eval_rule(raw: &str, seed: Option<u64>) -> Result<ScriptResult, RuntimeError> {
+    // The instance seeds of the rules are reset, so that `SEED` fully determines their behavior
+    RuleRng::reset_instance_seeds();
+
     let mut env = default_env();
     populate_env(&mut env);
 
@@ -582,7 +1083,27 @@ pub fn eval_rule(raw: &str) -> Result<(Option<BoxedRule>, Option<Shape>, Option<
     }
 
     let evaluation_result = eval_block(env.clone(), ast.into_iter())?;
-    let rule = get_rule(as_symbol(&evaluation_result)?)?;
+
+    let seed = match env.borrow().entries.get("SEED") {
+        Some(seed) => Some(extract_seed(seed)?),
+        None => seed
+    };
+
+    // The rules of the layers must be extracted before the cleanup
+    let layers = match env.borrow().entries.get("LAYERS") {
+        Some(layers) => Some(extract_layers(layers, seed)?),
+        None => None
+    };
+
+    let rule = match layers {
+        Some(_) => as_symbol(&evaluation_result).ok().and_then(|name| get_rule(name).ok()),
+        None => Some(get_rule(as_symbol(&evaluation_result)?)?),
+    };
+
+    let final_rule = match env.borrow().entries.get("FINAL") {
+        Some(final_rule) => Some(get_rule(as_symbol(final_rule)?)?),
+        None => None
+    };
 
     // Cleanup:
     RULES.with(|r| {
@@ -598,7 +1119,7 @@ pub fn eval_rule(raw: &str) -> Result<(Option<BoxedRule>, Option<Shape>, Option<
     });
 
     let shape = if let Some(shape) = env.borrow().entries.get("SHAPE") {
-        Some(extract_shape(shape)?)
+        Some(extract_shape(shape, seed)?)
     } else {
         None
     };
@@ -609,6 +1130,16 @@ pub fn eval_rule(raw: &str) -> Result<(Option<BoxedRule>, Option<Shape>, Option<
         _ => None
     };
 
+    let camera = match env.borrow().entries.get("CAMERA") {
+        Some(camera) => Some(extract_camera(camera)?),
+        None => None
+    };
+
+    let fit = match env.borrow().entries.get("SCALE") {
+        Some(value @ (Value::Symbol(_) | Value::List(_))) => Some(extract_fit(value)?),
+        _ => None
+    };
+
     let center = match env.borrow().entries.get("CENTER") {
         Some(Value::List(l)) => {
             let mut iter = l.into_iter();
@@ -623,7 +1154,56 @@ pub fn eval_rule(raw: &str) -> Result<(Option<BoxedRule>, Option<Shape>, Option<
         _ => None
     };
 
-    Ok((Some(rule), shape, scale, center))
+    let tone_map = match env.borrow().entries.get("TONE_MAP") {
+        Some(tone_map) => Some(extract_tone_map(tone_map)?),
+        None => None
+    };
+
+    let gain = match env.borrow().entries.get("GAIN") {
+        Some(gain) => Some(extract_gain(gain)?),
+        None => None
+    };
+
+    let splat = match env.borrow().entries.get("SPLAT") {
+        Some(splat) => Some(extract_splat(splat)?),
+        None => None
+    };
+
+    let density_estimation = match env.borrow().entries.get("DENSITY_ESTIMATION") {
+        Some(density_estimation) => Some(extract_density_estimation(density_estimation)?),
+        None => None
+    };
+
+    let background = match env.borrow().entries.get("BACKGROUND") {
+        Some(background) => Some(extract_background(background)?),
+        None => None
+    };
+
+    let color_space = match env.borrow().entries.get("COLOR_SPACE") {
+        Some(color_space) => Some(extract_color_space(color_space)?),
+        None => None
+    };
+
+    let wrap = env.borrow().entries.get("WRAP").map(|wrap| wrap.is_truthy());
+
+    Ok(ScriptResult {
+        rule,
+        final_rule,
+        shape,
+        scale,
+        fit,
+        center,
+        camera,
+        seed,
+        tone_map,
+        gain,
+        splat,
+        density_estimation,
+        background,
+        color_space,
+        wrap,
+        layers,
+    })
 }
 
 #[cfg(test)]
@@ -632,6 +1212,6 @@ mod test {
 
     #[test]
     fn test_parse() {
-        assert!(eval_rule("(or-rule 0.5 (advance-rule (choice) 0.25) (advance-rule (choice) 0.5))").is_ok());
+        assert!(eval_rule("(or-rule 0.5 (advance-rule (choice) 0.25) (advance-rule (choice) 0.5))", None).is_ok());
     }
 }
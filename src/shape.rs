@@ -1,4 +1,4 @@
-use super::GAMMA;
+use super::color::{srgb_to_linear, ColorSpace};
 
 #[derive(Clone, Copy, PartialEq)]
 pub struct Point {
@@ -36,9 +36,11 @@ impl Point {
         self.b = color.2;
     }
 
-    pub fn lightness(&self) -> f64 {
-        // Since we're in linear color space, we can just use the L = 0.2126 * r + 0.7152 * g + 0.0722 * b formula:
-        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    /// The relative luminance of the point, whose color is given in `color_space`
+    pub fn lightness(&self, color_space: ColorSpace) -> f64 {
+        let (r, g, b) = color_space.to_linear(self.color());
+        // Once in linear color space, we can just use the L = 0.2126 * r + 0.7152 * g + 0.0722 * b formula:
+        0.2126 * r + 0.7152 * g + 0.0722 * b
     }
 }
 
@@ -65,9 +67,9 @@ pub fn polygon(n: usize) -> Shape {
 
 pub fn from_srgb(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
     (
-        (r as f64 / 255.0).powf(GAMMA),
-        (g as f64 / 255.0).powf(GAMMA),
-        (b as f64 / 255.0).powf(GAMMA),
+        srgb_to_linear(r as f64 / 255.0),
+        srgb_to_linear(g as f64 / 255.0),
+        srgb_to_linear(b as f64 / 255.0),
     )
 }
 
@@ -0,0 +1,179 @@
+use super::parse_named;
+
+/// How the weight of a point is spread over the pixels around it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Splat {
+    /// The whole point lands on the pixel containing it
+    #[default]
+    Nearest,
+    /// The point is shared between the 4 pixels whose centers surround it, depending on its distance to them
+    Bilinear,
+    /// The point is shared between the pixels around it following a normalized gaussian of standard deviation `sigma`, in pixels
+    Gaussian {
+        sigma: f64,
+    },
+}
+
+/// The gaussian kernel is truncated at 3 sigmas, so this bounds its size to 19×19 pixels
+const MAX_RADIUS: usize = 9;
+
+impl Splat {
+    pub const MAX_SIGMA: f64 = MAX_RADIUS as f64 / 3.0;
+
+    /// Creates a splatting mode from its name and its optional parameters
+    pub fn new(name: &str, params: &[f64]) -> Result<Self, String> {
+        match (name, params) {
+            ("nearest", []) => Ok(Self::Nearest),
+            ("bilinear", []) => Ok(Self::Bilinear),
+            ("gaussian", []) => Ok(Self::Gaussian {sigma: 0.5}),
+            ("gaussian", [sigma]) => {
+                if *sigma > 0.0 && *sigma <= Self::MAX_SIGMA {
+                    Ok(Self::Gaussian {sigma: *sigma})
+                } else {
+                    Err(format!("Expected sigma to be between 0 and {}, got {}", Self::MAX_SIGMA, sigma))
+                }
+            }
+            ("nearest" | "bilinear" | "gaussian", _) => Err(format!("Too many parameters for splatting mode '{}'", name)),
+            _ => Err(format!("Unknown splatting mode '{}', expected one of nearest, bilinear or gaussian", name)),
+        }
+    }
+
+    /// The number of pixels that the shares of a point reach beyond the pixel containing it
+    pub fn radius(&self) -> usize {
+        match self {
+            Self::Nearest => 0,
+            Self::Bilinear => 1,
+            Self::Gaussian {sigma} => ((3.0 * sigma).ceil() as usize).min(MAX_RADIUS),
+        }
+    }
+
+    /// Calls `f` with the index of each pixel of a `width × height` frame that the point at `(x, y)`, in pixels, lands on,
+    /// and with the share of the point that this pixel receives; shares falling outside of the frame are dropped,
+    /// unless `wrap` is set, in which case they wrap around to the opposite side of the frame
+    #[inline]
+    pub fn splat(&self, x: f64, y: f64, width: usize, height: usize, wrap: bool, mut f: impl FnMut(usize, f64)) {
+        // Points far outside of the frame are culled, or wrapped around, before being cast to pixels, where they would saturate
+        let (x, y) = if wrap {
+            if !x.is_finite() || !y.is_finite() {
+                return;
+            }
+            (x.rem_euclid(width as f64), y.rem_euclid(height as f64))
+        } else {
+            let reach = (self.radius() + 1) as f64;
+            if !(x > -reach && y > -reach && x < width as f64 + reach && y < height as f64 + reach) {
+                return;
+            }
+            (x, y)
+        };
+
+        let mut add = |px: isize, py: isize, share: f64| {
+            let (px, py) = if wrap {
+                (px.rem_euclid(width as isize), py.rem_euclid(height as isize))
+            } else {
+                (px, py)
+            };
+
+            if px >= 0 && py >= 0 && (px as usize) < width && (py as usize) < height && share > 0.0 {
+                f(px as usize + py as usize * width, share);
+            }
+        };
+
+        match self {
+            Self::Nearest => add(x.floor() as isize, y.floor() as isize, 1.0),
+            Self::Bilinear => {
+                // Position relative to the center of the top-left pixel of the 2×2 neighbourhood
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+
+                add(x0, y0, (1.0 - tx) * (1.0 - ty));
+                add(x0 + 1, y0, tx * (1.0 - ty));
+                add(x0, y0 + 1, (1.0 - tx) * ty);
+                add(x0 + 1, y0 + 1, tx * ty);
+            }
+            Self::Gaussian {sigma} => {
+                let radius = self.radius();
+                let size = 2 * radius + 1;
+                let (cx, cy) = (x.floor() as isize - radius as isize, y.floor() as isize - radius as isize);
+
+                // The kernel is separable, so the weights along each axis are computed once
+                let mut wx = [0.0; 2 * MAX_RADIUS + 1];
+                let mut wy = [0.0; 2 * MAX_RADIUS + 1];
+                for i in 0..size {
+                    let dx = (cx + i as isize) as f64 + 0.5 - x;
+                    let dy = (cy + i as isize) as f64 + 0.5 - y;
+                    wx[i] = (-dx * dx / (2.0 * sigma * sigma)).exp();
+                    wy[i] = (-dy * dy / (2.0 * sigma * sigma)).exp();
+                }
+                let total = wx[..size].iter().sum::<f64>() * wy[..size].iter().sum::<f64>();
+
+                for (j, wy) in wy[..size].iter().enumerate() {
+                    for (i, wx) in wx[..size].iter().enumerate() {
+                        add(cx + i as isize, cy + j as isize, wx * wy / total);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses a splatting mode in the `name[:param]` format, for instance `gaussian:0.7`
+impl std::str::FromStr for Splat {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (name, params) = parse_named(raw)?;
+
+        Self::new(name, &params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_splat() {
+        for splat in [Splat::Nearest, Splat::Bilinear, Splat::Gaussian {sigma: 0.8}] {
+            let mut total = 0.0;
+            splat.splat(10.3, 7.9, 20, 20, false, |_, share| total += share);
+            assert!((total - 1.0).abs() < 1e-9);
+        }
+
+        let mut shares = Vec::new();
+        Splat::Bilinear.splat(1.75, 0.5, 4, 4, false, |index, share| shares.push((index, share)));
+        assert_eq!(shares, vec![(1, 0.75), (2, 0.25)]);
+
+        // Shares outside of the frame are dropped
+        let mut total = 0.0;
+        Splat::Gaussian {sigma: 1.0}.splat(0.0, 0.0, 4, 4, false, |_, share| total += share);
+        assert!(total < 0.5);
+
+        // Unless they wrap around
+        let mut total = 0.0;
+        Splat::Gaussian {sigma: 1.0}.splat(0.0, 0.0, 4, 4, true, |_, share| total += share);
+        assert!((total - 1.0).abs() < 1e-9);
+        let mut shares = Vec::new();
+        Splat::Nearest.splat(-0.5, 9.5, 4, 4, true, |index, share| shares.push((index, share)));
+        assert_eq!(shares, vec![(3 + 4, 1.0)]);
+
+        // Far-off points don't overflow, nor pile up on the edges of the frame
+        for splat in [Splat::Nearest, Splat::Bilinear, Splat::Gaussian {sigma: 1.0}] {
+            for (x, y) in [(1e300, 2.0), (-1e300, 2.0), (2.0, f64::MAX), (f64::NAN, 2.0)] {
+                let mut count = 0;
+                splat.splat(x, y, 4, 4, false, |_, _| count += 1);
+                assert_eq!(count, 0);
+            }
+            let mut count = 0;
+            splat.splat(f64::NAN, 2.0, 4, 4, true, |_, _| count += 1);
+            assert_eq!(count, 0);
+        }
+        let mut shares = Vec::new();
+        Splat::Nearest.splat(4e9 + 1.5, 2.5, 4, 4, true, |index, share| shares.push((index, share)));
+        assert_eq!(shares, vec![(1 + 2 * 4, 1.0)]);
+
+        assert_eq!("gaussian:1.5".parse::<Splat>(), Ok(Splat::Gaussian {sigma: 1.5}));
+        assert!("bilinear:1".parse::<Splat>().is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use super::world::Pixel;
+use super::{parse_named, BG_R, BG_G, BG_B};
+use super::color::encode_srgb;
+
+/// Maps the density of each pixel to its opacity `a ∈ [0, 1]`, which is then used to blend its color over the background.
+/// `ratio` is the gain, scaled such that `n * ratio = gain` for a pixel of average density.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ToneMap {
+    /// `a = 1 - exp(-n * ratio)`
+    #[default]
+    Exponential,
+    /// flam3-style log-density: `a = min(1, brightness * ln(1 + n * ratio))^(1 / contrast)`
+    LogDensity {
+        brightness: f64,
+        contrast: f64,
+    },
+    /// `a = min(1, n * ratio)`
+    Linear,
+    /// Histogram equalization: `a` is the fraction of lit pixels whose density is lower or equal to that of the pixel.
+    /// Ignores the gain.
+    Histogram,
+}
+
+/// The gain used to compute `ratio`, see `ToneMap`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gain {
+    Fixed(f64),
+    /// The gain is chosen such that the lit pixel at the `percentile` of hit counts reaches an opacity of `target`;
+    /// a fraction `1 - percentile` of the lit pixels will thus be at least that bright.
+    Auto {
+        percentile: f64,
+        target: f64,
+    },
+}
+
+/// What the pixels are blended over once their opacity is known
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    /// A color in linear RGB
+    Color(f64, f64, f64),
+    /// The opacity of each pixel becomes its alpha channel, so that the image can be composited over something else
+    Transparent,
+}
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self::Fixed(0.1)
+    }
+}
+
+impl Gain {
+    /// Auto gain with the default parameters
+    pub fn default_auto() -> Self {
+        Self::Auto {
+            percentile: 0.9,
+            target: 0.9,
+        }
+    }
+
+    pub fn auto(percentile: f64, target: f64) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&percentile) {
+            return Err(format!("Expected the percentile to be between 0 and 1, got {}", percentile));
+        }
+        if target <= 0.0 || target >= 1.0 {
+            return Err(format!("Expected the target brightness to be strictly between 0 and 1, got {}", target));
+        }
+
+        Ok(Self::Auto {percentile, target})
+    }
+}
+
+/// Parses either a number or `auto[:percentile[:target]]`
+impl std::str::FromStr for Gain {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut iter = raw.split(':');
+
+        if iter.next() == Some("auto") {
+            let params = iter
+                .map(|x| x.parse::<f64>().map_err(|e| format!("{:?}", e)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            match params[..] {
+                [] => Ok(Self::default_auto()),
+                [percentile] => Self::auto(percentile, 0.9),
+                [percentile, target] => Self::auto(percentile, target),
+                _ => Err(String::from("Expected at most two parameters for auto gain")),
+            }
+        } else {
+            raw.parse::<f64>().map(Self::Fixed).map_err(|e| format!("{:?}", e))
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Self::Color(BG_R, BG_G, BG_B)
+    }
+}
+
+impl Background {
+    /// Returns the encoded RGBA value of a pixel of linear color `color` and of opacity `a`
+    #[inline]
+    pub fn blend(&self, color: (f64, f64, f64), a: f64) -> [u8; 4] {
+        match self {
+            Self::Color(r, g, b) => [
+                encode_srgb(color.0 * a + r * (1.0 - a)),
+                encode_srgb(color.1 * a + g * (1.0 - a)),
+                encode_srgb(color.2 * a + b * (1.0 - a)),
+                255,
+            ],
+            Self::Transparent => [encode_srgb(color.0), encode_srgb(color.1), encode_srgb(color.2), (a * 255.0) as u8],
+        }
+    }
+
+    /// Returns the encoded RGBA value of a pixel that nothing landed on
+    #[inline]
+    pub fn empty(&self) -> [u8; 4] {
+        self.blend((0.0, 0.0, 0.0), 0.0)
+    }
+}
+
+/// Parses either `transparent`, an sRGB color in the `#rrggbb` format, or three sRGB components between 0 and 255 as `r,g,b`
+impl std::str::FromStr for Background {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if raw == "transparent" {
+            return Ok(Self::Transparent);
+        }
+
+        let components = if let Some(hex) = raw.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return Err(format!("Expected a color in the #rrggbb format, got '{}'", raw));
+            }
+
+            (0..3)
+                .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|e| format!("{:?}", e)))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            raw.split(',')
+                .map(|x| x.trim().parse::<u8>().map_err(|e| format!("{:?}", e)))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        match components[..] {
+            [r, g, b] => {
+                let (r, g, b) = super::shape::from_srgb(r, g, b);
+                Ok(Self::Color(r, g, b))
+            }
+            _ => Err(format!("Expected three color components, got '{}'", raw)),
+        }
+    }
+}
+
+impl ToneMap {
+    /// Creates a tone map from its name and its optional parameters
+    pub fn new(name: &str, params: &[f64]) -> Result<Self, String> {
+        let expect_params = |max: usize| {
+            if params.len() > max {
+                Err(format!("Tone map '{}' expects at most {} parameters, got {}", name, max, params.len()))
+            } else {
+                Ok(())
+            }
+        };
+
+        match name {
+            "exp" | "exponential" => {
+                expect_params(0)?;
+                Ok(Self::Exponential)
+            }
+            "log" | "log-density" => {
+                expect_params(2)?;
+                let brightness = params.first().copied().unwrap_or(1.0);
+                let contrast = params.get(1).copied().unwrap_or(1.0);
+                if !(brightness > 0.0 && contrast > 0.0) {
+                    return Err(format!("Expected the brightness and contrast to be positive, got {} and {}", brightness, contrast));
+                }
+
+                Ok(Self::LogDensity {brightness, contrast})
+            }
+            "linear" => {
+                expect_params(0)?;
+                Ok(Self::Linear)
+            }
+            "hist" | "histogram" => {
+                expect_params(0)?;
+                Ok(Self::Histogram)
+            }
+            _ => Err(format!("Unknown tone map '{}', expected one of exp, log, linear or hist", name)),
+        }
+    }
+
+    /// Returns the value of `n * ratio` for which the opacity is `a`, or `None` if the opacity doesn't depend on it
+    pub fn inverse(&self, a: f64) -> Option<f64> {
+        match self {
+            Self::Exponential => Some(-(1.0 - a).ln()),
+            Self::LogDensity {brightness, contrast} => Some((a.powf(*contrast) / brightness).exp_m1()),
+            Self::Linear => Some(a),
+            Self::Histogram => None,
+        }
+    }
+
+    /// Returns the opacity of each pixel
+    pub fn alphas(&self, pixels: &[Pixel], ratio: f64) -> Vec<f64> {
+        match self {
+            Self::Exponential => pixels.iter().map(|p| 1.0 - (-p.n * ratio).exp()).collect(),
+            Self::LogDensity {brightness, contrast} => pixels.iter().map(|p| {
+                (brightness * (p.n * ratio).ln_1p()).min(1.0).powf(1.0 / contrast)
+            }).collect(),
+            Self::Linear => pixels.iter().map(|p| (p.n * ratio).min(1.0)).collect(),
+            Self::Histogram => {
+                let mut densities = pixels.iter().map(|p| p.n).filter(|n| *n > 0.0).collect::<Vec<_>>();
+                densities.sort_by(|a, b| a.total_cmp(b));
+                let count = densities.len() as f64;
+
+                pixels.iter().map(|p| {
+                    if p.n > 0.0 {
+                        densities.partition_point(|n| *n <= p.n) as f64 / count
+                    } else {
+                        0.0
+                    }
+                }).collect()
+            }
+        }
+    }
+}
+
+/// Parses a tone map in the `name[:param...]` format, for instance `log:2.0:1.5`
+impl std::str::FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (name, params) = parse_named(raw)?;
+
+        Self::new(name, &params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let pixels = [0.0, 4.0, 1.0, 4.0, 2.0].into_iter().map(|n| Pixel {
+            n,
+            ..Pixel::default()
+        }).collect::<Vec<_>>();
+
+        assert_eq!(ToneMap::Histogram.alphas(&pixels, 1.0), vec![0.0, 1.0, 0.25, 1.0, 0.5]);
+        assert_eq!("log:2:0.5".parse::<ToneMap>(), Ok(ToneMap::LogDensity {brightness: 2.0, contrast: 0.5}));
+        assert!("exp:1".parse::<ToneMap>().is_err());
+        assert!("log:0:1".parse::<ToneMap>().is_err());
+        assert!("log:-1".parse::<ToneMap>().is_err());
+        assert!("log:1:0".parse::<ToneMap>().is_err());
+    }
+
+    #[test]
+    fn test_auto_gain() {
+        use super::super::world::State;
+
+        let pixels = (0..100).map(|n| Pixel {
+            n: n as f64,
+            ..Pixel::default()
+        }).collect::<Vec<_>>();
+        let state = State::new(pixels, 4950, 10, 10);
+        let gain = Gain::auto(0.5, 0.8).unwrap();
+
+        for tone_map in [ToneMap::Exponential, ToneMap::LogDensity {brightness: 0.5, contrast: 2.0}, ToneMap::Linear] {
+            let ratio = state.gain(gain, &tone_map) * 100.0 / 4950.0;
+            // The median of 1..=99 is 50
+            let alpha = tone_map.alphas(&state.pixels[50..51], ratio)[0];
+            assert!((alpha - 0.8).abs() < 1e-9);
+        }
+
+        assert_eq!("auto:0.5".parse::<Gain>(), Ok(Gain::Auto {percentile: 0.5, target: 0.9}));
+        assert!("auto:0.5:1.0".parse::<Gain>().is_err());
+    }
+
+    #[test]
+    fn test_background() {
+        assert_eq!("#ff0000".parse::<Background>(), Ok(Background::Color(1.0, 0.0, 0.0)));
+        assert_eq!("255, 0, 0".parse::<Background>(), Ok(Background::Color(1.0, 0.0, 0.0)));
+        assert!("#ff00".parse::<Background>().is_err());
+
+        let white = Background::Color(1.0, 1.0, 1.0);
+        assert_eq!(white.blend((0.0, 0.0, 0.0), 1.0), [0, 0, 0, 255]);
+        assert_eq!(white.empty(), [255, 255, 255, 255]);
+        assert_eq!(Background::Transparent.blend((1.0, 1.0, 1.0), 0.5), [255, 255, 255, 127]);
+        assert_eq!(Background::Transparent.empty(), [0, 0, 0, 0]);
+    }
+}
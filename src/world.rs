@@ -1,6 +1,13 @@
 use super::rules::*;
 use super::shape::*;
-use super::*;
+use super::camera::Camera;
+use super::color::ColorSpace;
+use super::filter::{DensityEstimation, Supersample};
+use super::splat::Splat;
+use super::tonemap::{Background, Gain, ToneMap};
+use rand::{Rng, SeedableRng};
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::sync::mpsc::{TrySendError, Receiver};
 use std::sync::{Arc, Mutex};
 use worker_pool::{DownMsg, WorkerPool, WorkerSender};
@@ -33,7 +40,8 @@ impl Default for Pixel {
 }
 
 impl Pixel {
-    pub fn add(&mut self, point: Point) {
+    /// Adds a point whose color is given in `color_space`, which is needed to measure its lightness with the `sigma` feature
+    pub fn add(&mut self, point: Point, color_space: ColorSpace) {
         self.r_sum += point.r * point.weight;
         self.g_sum += point.g * point.weight;
         self.b_sum += point.b * point.weight;
@@ -41,10 +49,42 @@ impl Pixel {
 
         #[cfg(feature = "sigma")]
         if true {
-            let lightness = point.lightness();
+            let lightness = point.lightness(color_space);
             self.l_sum += lightness * point.weight;
             self.l_squared += lightness * lightness * point.weight;
         }
+        #[cfg(not(feature = "sigma"))]
+        let _ = color_space;
+    }
+
+    pub fn add_scaled(&mut self, other: &Pixel, weight: f64) {
+        self.r_sum += other.r_sum * weight;
+        self.g_sum += other.g_sum * weight;
+        self.b_sum += other.b_sum * weight;
+        self.n += other.n * weight;
+
+        #[cfg(feature = "sigma")]
+        if true {
+            self.l_sum += other.l_sum * weight;
+            self.l_squared += other.l_squared * weight;
+        }
+    }
+
+    /// Clears the pixel if its density is negative, and otherwise sets its negative lightness sums to zero.
+    /// Only `r_sum` is clamped among the colors: in Oklab, `g_sum` and `b_sum` hold the a and b channels, which are signed
+    pub fn clamp_negative(&mut self) {
+        if self.n <= 0.0 {
+            *self = Pixel::default();
+            return;
+        }
+
+        self.r_sum = self.r_sum.max(0.0);
+
+        #[cfg(feature = "sigma")]
+        if true {
+            self.l_sum = self.l_sum.max(0.0);
+            self.l_squared = self.l_squared.max(0.0);
+        }
     }
 
     pub fn add_pixel(&mut self, other: Pixel) {
@@ -78,17 +118,137 @@ impl Pixel {
     pub fn error_squared(&self) -> f64 {
         0.0
     }
+
+    #[inline]
+    #[cfg(feature = "sigma")]
+    pub fn mean_lightness(&self) -> f64 {
+        if self.n == 0.0 {
+            0.0
+        } else {
+            self.l_sum / self.n
+        }
+    }
+
+    #[inline]
+    #[cfg(not(feature = "sigma"))]
+    pub fn mean_lightness(&self) -> f64 {
+        0.0
+    }
+
+    /// The standard error of the lightness of the pixel relative to its mean lightness, capped to 1;
+    /// pixels hit less than twice have no meaningful variance, so their error is 1
+    pub fn relative_error(&self) -> f64 {
+        let mean = self.mean_lightness();
+
+        if self.n < 2.0 {
+            1.0
+        } else if mean <= 0.0 {
+            0.0
+        } else {
+            (self.error_squared().max(0.0).sqrt() / mean).min(1.0)
+        }
+    }
 }
 
 pub struct WorldParams<R: Rule> {
     pub zoom: f64,
     pub center: (f64, f64),
+    /// Rotates, stretches and flips the view around its center
+    pub camera: Camera,
+    /// If set, the frame is treated as a torus: the points falling outside of it wrap around to the opposite side,
+    /// so that the image tiles seamlessly
+    pub wrap: bool,
     pub rule: RuleBox<R>,
+    /// If set, applied to the points before they are drawn, like the final transform of a fractal flame;
+    /// its output is never fed back into the orbit, so it warps the image without changing the dynamics of `rule`
+    pub final_rule: Option<RuleBox<R>>,
     pub steps: usize,
     pub scatter_steps: usize,
     pub burnin_steps: usize,
     pub shape: Shape,
-    pub gain: f64,
+    pub gain: Gain,
+    pub tone_map: ToneMap,
+    /// If set, the seed of every worker is derived from it, making renders reproducible: with the same number of threads and `max_steps`,
+    /// the workers draw the same points and the manager sums their batches in the same order, so the image is identical
+    pub seed: Option<u64>,
+    /// If set, the workers will split this amount of steps between themselves and stop once it is reached
+    pub max_steps: Option<usize>,
+    pub accumulation: Accumulation,
+    pub splat: Splat,
+    /// The workers accumulate at `supersample.factor` times the resolution of the image
+    pub supersample: Supersample,
+    /// If set, applied to the accumulation buffer before tone mapping it
+    pub density_estimation: Option<DensityEstimation>,
+    pub background: Background,
+    /// The color space in which the colors of the points are blended and accumulated
+    pub color_space: ColorSpace,
+    /// If set, the manager stops the workers once the noise of the render falls below its target
+    pub convergence: Option<Convergence>,
+}
+
+/// How the workers accumulate their points before sending them to the manager
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Accumulation {
+    /// Each worker owns a buffer of `width * height` pixels, which is fast but needs a lot of memory for large images
+    #[default]
+    Dense,
+    /// Each worker sends the points that landed in the frame by batches of at most `batch_size` points,
+    /// bounding memory usage regardless of the size of the image
+    Sparse {
+        batch_size: usize,
+    },
+}
+
+impl Accumulation {
+    pub const DEFAULT_BATCH_SIZE: usize = 1 << 18;
+}
+
+/// How the noise of a render is measured, from the variance of the lightness of the points that landed on each pixel;
+/// this needs the `sigma` feature, without which the noise is always zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Convergence {
+    /// The render has converged once `State::mse` falls below the target
+    Mse(f64),
+    /// The render has converged once `State::relative_noise` falls below the target
+    RelativeNoise(f64),
+}
+
+impl Default for Convergence {
+    fn default() -> Self {
+        Self::Mse(0.0)
+    }
+}
+
+impl Convergence {
+    pub fn noise(&self, state: &State) -> f64 {
+        match self {
+            Self::Mse(_) => state.mse(),
+            Self::RelativeNoise(_) => state.relative_noise(),
+        }
+    }
+
+    pub fn target(&self) -> f64 {
+        match self {
+            Self::Mse(target) | Self::RelativeNoise(target) => *target,
+        }
+    }
+}
+
+/// Parses either `dense` or `sparse[:batch_size]`
+impl std::str::FromStr for Accumulation {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.split_once(':') {
+            None if raw == "dense" => Ok(Self::Dense),
+            None if raw == "sparse" => Ok(Self::Sparse {batch_size: Self::DEFAULT_BATCH_SIZE}),
+            Some(("sparse", batch_size)) => match batch_size.parse::<usize>() {
+                Ok(batch_size) if batch_size > 0 => Ok(Self::Sparse {batch_size}),
+                _ => Err(format!("Expected a positive batch size, got '{}'", batch_size)),
+            },
+            _ => Err(format!("Unknown accumulation '{}', expected dense or sparse[:batch_size]", raw)),
+        }
+    }
 }
 
 pub struct World {
@@ -96,9 +256,15 @@ pub struct World {
     height: usize,
 
     pub state: Arc<Mutex<Image>>,
-    manager: WorkerPool<(), ManagerMsg>,
+    manager: WorkerPool<State, ManagerMsg>,
+    accumulation: Option<State>,
+    supersample: Supersample,
+    background: Background,
+    color_space: ColorSpace,
+    wrap: bool,
 }
 
+#[derive(Clone)]
 pub struct State {
     pub pixels: Vec<Pixel>,
     pub steps: usize,
@@ -108,14 +274,43 @@ pub struct State {
 
 pub struct Image {
     pub pixels: Vec<u8>,
+    /// The premultiplied linear RGBA of each pixel, only filled over a transparent background,
+    /// so that layered scenes can composite the renders before quantizing them
+    pub linear: Vec<[f64; 4]>,
     pub steps: usize,
     pub width: usize,
-    pub height: usize
+    pub height: usize,
+    /// The noise of the accumulation buffer, measured with `WorldParams::convergence`, only set with the `sigma` feature
+    pub noise: Option<f64>,
+    /// True once the noise reached the target of `WorldParams::convergence`, after which the workers are stopped
+    pub converged: bool,
+}
+
+/// What a worker accumulated since its last message
+enum Batch {
+    Dense(State),
+    /// The points that landed in the frame, along with the index of their pixel
+    Sparse {
+        points: Vec<(usize, Point)>,
+        steps: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+/// A batch, along with the worker that sent it
+struct WorkerMsg {
+    worker: usize,
+    /// Set once the worker spent its budget, after which it only sends a batch when it is stopped
+    last: bool,
+    batch: Batch,
 }
 
 #[derive(Clone, Debug)]
 enum ManagerMsg {
     Resize(usize, usize),
+    /// Asks the manager to send a copy of its accumulation buffer; never sent to the workers
+    Snapshot,
 }
 
 // Acts as a middle-man between World and Worker; takes all the data sent by the workers and puts it into result_buffer
@@ -125,18 +320,38 @@ struct Manager<R: Rule> {
     result_buffer: Arc<Mutex<Image>>,
 
     params: WorldParams<R>,
-    workers: WorkerPool<State, ManagerMsg>,
+    workers: WorkerPool<WorkerMsg, ManagerMsg>,
     n_threads: usize,
+    /// Set once the render converged and the workers were stopped
+    converged: bool,
+
+    /// The number of iterations of each worker, if `max_steps` is set
+    budgets: Vec<Option<usize>>,
+    /// Only used with a seed: the batches of each worker that weren't summed yet, see `Manager::combine_pending`
+    pending: Vec<VecDeque<Batch>>,
+    /// Only used with a seed: whether each worker spent its budget
+    done: Vec<bool>,
+    /// Only used with a seed: the worker whose batch is summed next
+    next_worker: usize,
 }
 
 struct Worker<R: Rule + 'static> {
+    /// Only used with `Accumulation::Dense`
     pixels: Vec<Pixel>,
+    /// Only used with `Accumulation::Sparse`
+    points: Vec<(usize, Point)>,
 
     width: usize,
     height: usize,
-    ratio: f64,
+    /// Maps the offset of a point from the center of the view to its offset in pixels; see `Camera::matrix`
+    transform: [f64; 4],
     steps: usize,
 
+    index: usize,
+    seed: [u8; 32],
+    budget: Option<usize>,
+    remaining: Option<usize>,
+
     params: WorldParams<R>,
 }
 
@@ -148,11 +363,29 @@ impl World {
         n_threads: usize,
         queue_length: usize
     ) -> Self {
-        let width = width as usize;
-        let height = height as usize;
+        let factor = params.supersample.factor;
+        let state = State::empty(width as usize * factor, height as usize * factor);
+
+        Self::with_state(state, params, n_threads, queue_length)
+    }
+
+    /// Creates a world that keeps accumulating into `state`, for instance to resume a render from a checkpoint;
+    /// `state` must be at the supersampled resolution
+    pub fn with_state<R: Rule + 'static>(
+        state: State,
+        params: WorldParams<R>,
+        n_threads: usize,
+        queue_length: usize
+    ) -> Self {
+        let supersample = params.supersample;
+        let background = params.background;
+        let color_space = params.color_space;
+        let wrap = params.wrap;
+        let width = state.width / supersample.factor;
+        let height = state.height / supersample.factor;
 
         let result_buffer = Arc::new(Mutex::new(
-            Image::empty(width, height)
+            Image::empty(width, height, background)
         ));
         let mut manager = WorkerPool::new(1);
 
@@ -163,9 +396,14 @@ impl World {
                     params,
                     workers: WorkerPool::new(queue_length),
                     n_threads,
-                    state: State::empty(width, height),
-                    tmp_buffer: Image::empty(width, height),
-                    result_buffer
+                    state,
+                    tmp_buffer: Image::empty(width, height, background),
+                    result_buffer,
+                    converged: false,
+                    budgets: Vec::new(),
+                    pending: Vec::new(),
+                    done: Vec::new(),
+                    next_worker: 0,
                 };
 
                 instance.run(tx, rx);
@@ -177,7 +415,12 @@ impl World {
             height,
 
             manager,
-            state: result_buffer
+            state: result_buffer,
+            accumulation: None,
+            supersample,
+            background,
+            color_space,
+            wrap,
         }
     }
 
@@ -190,7 +433,39 @@ impl World {
     }
 
     pub fn stop(&mut self) {
-        for _ in self.manager.stop() {}
+        if let Some(state) = self.manager.stop().last() {
+            self.accumulation = Some(state);
+        }
+    }
+
+    /// Returns the accumulation buffer of the manager, at the supersampled resolution;
+    /// it is only available once the world is stopped
+    pub fn accumulation(&self) -> Option<&State> {
+        self.accumulation.as_ref()
+    }
+
+    pub fn supersample(&self) -> Supersample {
+        self.supersample
+    }
+
+    /// The color space in which the accumulation buffer is stored
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// Whether the accumulation buffer wraps around its edges
+    pub fn wrap(&self) -> bool {
+        self.wrap
+    }
+
+    /// Returns a copy of the accumulation buffer of the manager, waiting for it to answer
+    pub fn snapshot(&mut self) -> Option<State> {
+        if self.accumulation.is_some() {
+            return self.accumulation.clone();
+        }
+
+        self.manager.broadcast(DownMsg::Other(ManagerMsg::Snapshot));
+        self.manager.recv().ok()
     }
 
     pub fn resize(&mut self, width: u32, height: u32) {
@@ -207,27 +482,43 @@ impl World {
                     *target = *src;
                 }
             } else {
-                let bg_r = (BG_R.powf(1.0 / GAMMA) * 255.0) as u8;
-                let bg_g = (BG_G.powf(1.0 / GAMMA) * 255.0) as u8;
-                let bg_b = (BG_B.powf(1.0 / GAMMA) * 255.0) as u8;
+                let empty = self.background.empty();
 
                 for pixel in frame.chunks_exact_mut(4) {
-                    pixel[0] = bg_r;
-                    pixel[1] = bg_g;
-                    pixel[2] = bg_b;
-                    pixel[3] = 255;
+                    pixel.copy_from_slice(&empty);
                 }
             }
         }
     }
 
+    /// Copies the premultiplied linear colors of the last image to `frame`, which stays transparent unless the background is
+    pub fn draw_linear(&self, frame: &mut [[f64; 4]]) {
+        if let Ok(state) = self.state.lock() {
+            if state.width == self.width && state.height == self.height && frame.len() == state.linear.len() {
+                frame.copy_from_slice(&state.linear);
+            } else {
+                frame.fill([0.0; 4]);
+            }
+        }
+    }
+
     pub fn steps(&self) -> usize {
         self.state.lock().unwrap().steps
     }
+
+    /// Returns the last measured noise of the render, if the `sigma` feature is enabled
+    pub fn noise(&self) -> Option<f64> {
+        self.state.lock().unwrap().noise
+    }
+
+    /// Returns true once the render reached the noise target of `WorldParams::convergence`
+    pub fn converged(&self) -> bool {
+        self.state.lock().unwrap().converged
+    }
 }
 
 impl<R: Rule + 'static> Manager<R> {
-    fn run(mut self, _tx: WorkerSender<()>, rx: Receiver<DownMsg<ManagerMsg>>) {
+    fn run(mut self, tx: WorkerSender<State>, rx: Receiver<DownMsg<ManagerMsg>>) {
         self.spawn_threads();
 
         loop {
@@ -237,39 +528,126 @@ impl<R: Rule + 'static> Manager<R> {
                         self.resize(width, height);
                         continue;
                     }
+                    ManagerMsg::Snapshot => {
+                        let _ = tx.send(self.state.clone());
+                        continue;
+                    }
                 }
             }
 
-            self.update();
+            if !self.converged {
+                self.update();
+            }
             std::thread::sleep(std::time::Duration::new(0, 10_000_000));
         }
 
-        self.stop();
+        if !self.converged {
+            self.stop();
+        }
         self.draw();
+
+        // Hand the accumulation buffer over to World
+        let _ = tx.send(self.state);
     }
 
     fn stop(&mut self) {
-        for msg in self.workers.stop() {
-            self.state.combine(msg);
+        let msgs = self.workers.stop().collect::<Vec<_>>();
+        for msg in msgs {
+            self.receive(msg);
         }
+
+        // The workers are all joined, so none of them has anything left to send
+        self.done.fill(true);
+        self.combine_pending();
     }
 
     fn update(&mut self) {
         let mut received_msg = false;
-        for msg in self.workers.recv_burst() {
-            self.state.combine(msg);
-            received_msg = true;
+        let msgs = self.workers.recv_burst().collect::<Vec<_>>();
+        for msg in msgs {
+            received_msg |= self.receive(msg);
+        }
+        if self.params.seed.is_some() {
+            received_msg |= self.combine_pending();
         }
 
         if received_msg {
+            if self.check_convergence() {
+                self.stop();
+                self.converged = true;
+            }
             self.draw();
         }
     }
 
+    /// Sums a batch right away, or queues it if the render is seeded; returns true if it was summed
+    fn receive(&mut self, msg: WorkerMsg) -> bool {
+        if self.params.seed.is_none() {
+            self.state.combine_batch(msg.batch, self.params.color_space);
+            return true;
+        }
+
+        self.done[msg.worker] |= msg.last;
+        self.pending[msg.worker].push_back(msg.batch);
+        false
+    }
+
+    /// Sums the queued batches round by round, in the order of the workers, so that the result doesn't depend on scheduling;
+    /// waits at the first worker whose next batch hasn't arrived yet, unless it spent its budget. Returns true if anything was summed
+    fn combine_pending(&mut self) -> bool {
+        let mut combined = false;
+        let mut skipped = 0;
+
+        while skipped < self.pending.len() {
+            let worker = self.next_worker;
+            match self.pending[worker].pop_front() {
+                Some(batch) => {
+                    self.state.combine_batch(batch, self.params.color_space);
+                    combined = true;
+                    skipped = 0;
+                }
+                None if self.done[worker] => skipped += 1,
+                None => break,
+            }
+            self.next_worker = (worker + 1) % self.pending.len();
+        }
+
+        combined
+    }
+
+    /// Returns true if the noise of the accumulation buffer fell below the convergence target.
+    /// Pixels that were hit only a few times have an unreliable variance, so this waits for every worker to do a full iteration.
+    fn check_convergence(&self) -> bool {
+        let warmup = self.n_threads * self.params.steps * (1 + self.params.scatter_steps);
+
+        match self.params.convergence {
+            Some(convergence) => self.state.steps >= warmup && convergence.noise(&self.state) <= convergence.target(),
+            None => false,
+        }
+    }
+
     fn draw(&mut self) {
-        debug_assert!(self.tmp_buffer.width == self.state.width && self.tmp_buffer.height == self.state.height);
-        self.state.draw(&mut self.tmp_buffer.pixels, self.params.gain);
-        self.tmp_buffer.steps = self.state.steps;
+        let factor = self.params.supersample.factor;
+        debug_assert!(self.tmp_buffer.width * factor == self.state.width && self.tmp_buffer.height * factor == self.state.height);
+
+        let mut state = Cow::Borrowed(&self.state);
+        if factor > 1 {
+            state = Cow::Owned(self.params.supersample.downsample(&state, self.params.wrap));
+        }
+        if let Some(density_estimation) = self.params.density_estimation {
+            state = Cow::Owned(density_estimation.apply(&state, self.params.wrap));
+        }
+
+        let gain = state.gain(self.params.gain, &self.params.tone_map);
+        state.draw(&mut self.tmp_buffer.pixels, gain, &self.params.tone_map, self.params.background, self.params.color_space);
+        if self.params.background == Background::Transparent {
+            state.draw_linear(&mut self.tmp_buffer.linear, gain, &self.params.tone_map, self.params.color_space);
+        }
+        self.tmp_buffer.steps = state.steps;
+        self.tmp_buffer.noise = cfg!(feature = "sigma").then(|| {
+            self.params.convergence.unwrap_or_default().noise(&self.state)
+        });
+        self.tmp_buffer.converged = self.converged;
 
         if let Ok(mut result_buffer) = self.result_buffer.lock() {
             std::mem::swap(&mut *result_buffer, &mut self.tmp_buffer);
@@ -277,57 +655,110 @@ impl<R: Rule + 'static> Manager<R> {
     }
 
     fn resize(&mut self, width: usize, height: usize) {
-        self.state.reset(width, height);
+        let factor = self.params.supersample.factor;
+        self.state.reset(width * factor, height * factor);
 
-        self.workers.broadcast(DownMsg::Other(ManagerMsg::Resize(width, height)));
+        self.workers.broadcast(DownMsg::Other(ManagerMsg::Resize(width * factor, height * factor)));
+        for pending in self.pending.iter_mut() {
+            pending.clear();
+        }
+        // A worker with an empty budget never sends anything before being stopped
+        self.done = self.budgets.iter().map(|budget| *budget == Some(0)).collect();
+        self.next_worker = 0;
 
-        self.tmp_buffer = Image::empty(width, height);
-        *self.result_buffer.lock().unwrap() = Image::empty(width, height);
+        self.tmp_buffer = Image::empty(width, height, self.params.background);
+        *self.result_buffer.lock().unwrap() = Image::empty(width, height, self.params.background);
         // for _ in self.workers.stop() {}
         // self.spawn_threads();
     }
 
     fn spawn_threads(&mut self) {
-        let params = self.params.clone();
-        let pixels = vec![Pixel::default(); self.state.width * self.state.height];
         let width = self.state.width;
         let height = self.state.height;
-        self.workers.execute_many(self.n_threads, move |tx, rx| {
-            let worker = Worker {
-                pixels,
-                width,
-                height,
-                params,
-                steps: 0,
-                ratio: 0.0,
-            };
 
-            worker.run(tx, rx);
+        // Number of iterations that all of the workers need to do to reach max_steps, not counting the steps of a resumed render
+        let iterations = self.params.max_steps.map(|max_steps| {
+            max_steps.saturating_sub(self.state.steps).div_ceil(1 + self.params.scatter_steps)
         });
+
+        self.budgets = (0..self.n_threads).map(|index| iterations.map(|iterations| {
+            iterations / self.n_threads + usize::from(index < iterations % self.n_threads)
+        })).collect();
+        self.pending = (0..self.n_threads).map(|_| VecDeque::new()).collect();
+        self.done = self.budgets.iter().map(|budget| *budget == Some(0)).collect();
+        self.next_worker = 0;
+
+        for index in 0..self.n_threads {
+            let mut params = self.params.clone();
+            params.shape = params.color_space.convert_shape(&params.shape);
+            let pixels = match self.params.accumulation {
+                Accumulation::Dense => vec![Pixel::default(); width * height],
+                Accumulation::Sparse {..} => Vec::new(),
+            };
+            let seed = match self.params.seed {
+                Some(seed) => worker_seed(seed, index),
+                None => rand::thread_rng().gen(),
+            };
+            let budget = self.budgets[index];
+
+            self.workers.execute(move |tx, rx| {
+                let worker = Worker {
+                    pixels,
+                    points: Vec::new(),
+                    width,
+                    height,
+                    params,
+                    steps: 0,
+                    transform: [0.0; 4],
+                    index,
+                    seed,
+                    budget,
+                    remaining: budget,
+                };
+
+                worker.run(tx, rx);
+            });
+        }
     }
 }
 
 impl<R: Rule> Worker<R> {
-    pub fn run(mut self, tx: WorkerSender<State>, rx: Receiver<DownMsg<ManagerMsg>>) {
-        use rand::Rng;
-
-        self.params.rule.reseed(&rand::thread_rng().gen());
-        self.ratio = self.width.min(self.height) as f64 / self.params.zoom / 2.0;
+    pub fn run(mut self, tx: WorkerSender<WorkerMsg>, rx: Receiver<DownMsg<ManagerMsg>>) {
+        self.params.rule.reseed(&self.seed);
+        if let Some(final_rule) = self.params.final_rule.as_mut() {
+            // The final rule gets its own stream, so that its random draws don't mirror those of the rule
+            let mut seed = self.seed;
+            seed.reverse();
+            final_rule.reseed(&seed);
+        }
+        self.update_transform();
 
         let mut first_iteration = true;
 
         loop {
-            if let Some(msg) = worker_pool::try_recv_break!(rx) {
+            // Once the budget is spent, wait for the manager to either stop or resize this worker
+            let msg = if self.remaining == Some(0) {
+                Some(worker_pool::recv_break!(rx))
+            } else {
+                worker_pool::try_recv_break!(rx)
+            };
+
+            if let Some(msg) = msg {
                 match msg {
                     ManagerMsg::Resize(width, height) => {
                         self.width = width;
                         self.height = height;
                         self.steps = 0;
-                        self.pixels = vec![Pixel::default(); width * height];
-                        self.ratio = self.width.min(self.height) as f64 / self.params.zoom / 2.0;
+                        if self.params.accumulation == Accumulation::Dense {
+                            self.pixels = vec![Pixel::default(); width * height];
+                        }
+                        self.points.clear();
+                        self.update_transform();
+                        self.remaining = self.budget;
                         first_iteration = true;
                         continue;
                     }
+                    ManagerMsg::Snapshot => {}
                 }
             }
 
@@ -340,6 +771,7 @@ impl<R: Rule> Worker<R> {
             } else {
                 self.params.steps
             };
+            let n_steps = self.remaining.map(|remaining| remaining.min(n_steps)).unwrap_or(n_steps);
 
             for _n in 0..self.params.burnin_steps {
                 let (new_point, new_index) = self.params.rule.next(point, &history, &self.params.shape, false);
@@ -356,7 +788,7 @@ impl<R: Rule> Worker<R> {
                         self.params
                             .rule
                             .next(point, &history, &self.params.shape, true);
-                    self.draw_pixel(new_point);
+                    self.plot(new_point, &history, true);
                 }
 
                 let (new_point, new_index) =
@@ -367,65 +799,170 @@ impl<R: Rule> Worker<R> {
                 history.rotate_right(1);
                 history[0] = new_index;
 
-                self.draw_pixel(new_point);
+                self.plot(new_point, &history, false);
                 point = new_point;
+
+                // Flush the points early to keep the batches bounded; the steps are counted in the last batch of the iteration
+                if let Accumulation::Sparse {batch_size} = self.params.accumulation {
+                    if self.points.len() >= batch_size {
+                        self.send_points(&tx, 0);
+                    }
+                }
             }
 
             self.steps += n_steps * (1 + self.params.scatter_steps);
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= n_steps;
+            }
+
+            if let Accumulation::Sparse {..} = self.params.accumulation {
+                // The points can't be kept around while the queue is full without growing unbounded
+                self.send_points(&tx, self.steps);
+                self.steps = 0;
+                continue;
+            }
+
+            let batch = Batch::Dense(State::new(std::mem::take(&mut self.pixels), self.steps, self.width, self.height));
+            let msg = self.message(batch);
+            let sent = if self.remaining == Some(0) || self.params.seed.is_some() {
+                // Make sure that the last results of this worker reach the manager, and that the batches of seeded renders
+                // always cover the same iterations
+                tx.send(msg).map_err(|e| TrySendError::Disconnected(e.0))
+            } else {
+                tx.try_send(msg)
+            };
 
-            match tx.try_send(State::new(self.pixels, self.steps, self.width, self.height)) {
+            match sent {
                 Ok(_) => {
                     self.pixels = vec![Pixel::default(); self.width * self.height];
                     self.steps = 0;
                 }
-                Err(TrySendError::Full(msg)) => self.pixels = msg.pixels,
-                Err(TrySendError::Disconnected(_)) => panic!("Manager disconnected!"),
+                Err(TrySendError::Full(WorkerMsg {batch: Batch::Dense(state), ..})) => self.pixels = state.pixels,
+                Err(_) => panic!("Manager disconnected!"),
             }
         }
 
-        tx.send(State::new(self.pixels, self.steps, self.width, self.height)).unwrap();
+        match self.params.accumulation {
+            Accumulation::Dense => {
+                let batch = Batch::Dense(State::new(std::mem::take(&mut self.pixels), self.steps, self.width, self.height));
+                tx.send(self.message(batch)).unwrap();
+            }
+            Accumulation::Sparse {..} => self.send_points(&tx, self.steps),
+        }
     }
 
+    fn message(&self, batch: Batch) -> WorkerMsg {
+        WorkerMsg {
+            worker: self.index,
+            last: self.remaining == Some(0),
+            batch,
+        }
+    }
+
+    /// Sends the accumulated points to the manager, blocking if its queue is full
+    fn send_points(&mut self, tx: &WorkerSender<WorkerMsg>, steps: usize) {
+        let batch = Batch::Sparse {
+            points: std::mem::take(&mut self.points),
+            steps,
+            width: self.width,
+            height: self.height,
+        };
+
+        if tx.send(self.message(batch)).is_err() {
+            panic!("Manager disconnected!");
+        }
+    }
+
+    fn update_transform(&mut self) {
+        let ratio = self.width.min(self.height) as f64 / self.params.zoom / 2.0;
+        self.transform = self.params.camera.matrix().map(|x| x * ratio);
+    }
+
+    /// Returns the position of a point in pixels, from the top-left corner of the frame
     #[inline]
-    pub fn get_coord(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+    pub fn get_position(&self, x: f64, y: f64) -> (f64, f64) {
         let cx = self.width as f64 / 2.0;
         let cy = self.height as f64 / 2.0;
+        let (dx, dy) = self.params.camera.projection.apply((x - self.params.center.0, y - self.params.center.1));
+        let [a, b, c, d] = self.transform;
 
-        let x = ((x - self.params.center.0) * self.ratio + cx).floor();
-        let y = ((y - self.params.center.1) * self.ratio + cy).floor();
+        (
+            a * dx + b * dy + cx,
+            c * dx + d * dy + cy,
+        )
+    }
 
-        if x < 0.0 || y < 0.0 {
-            return None;
-        }
+    /// Draws a point of the orbit, through the final rule if there is one
+    #[inline]
+    fn plot(&mut self, point: Point, history: &[usize], scatter: bool) {
+        let point = match self.params.final_rule.as_mut() {
+            Some(final_rule) => final_rule.next(point, history, &self.params.shape, scatter).0,
+            None => point,
+        };
 
-        let x = x as usize;
-        let y = y as usize;
-        if x < self.width && y < self.height {
-            Some((x, y))
-        } else {
-            None
-        }
+        self.draw_pixel(point);
     }
 
     #[inline]
     pub fn draw_pixel(&mut self, point: Point) {
-        if let Some((x, y)) = self.get_coord(point.x, point.y) {
-            self.pixels[x + y * self.width].add(point);
+        let (x, y) = self.get_position(point.x, point.y);
+        // Projections are undefined at their center
+        if !x.is_finite() || !y.is_finite() {
+            return;
         }
+        let accumulation = self.params.accumulation;
+        let color_space = self.params.color_space;
+        let pixels = &mut self.pixels;
+        let points = &mut self.points;
+
+        self.params.splat.splat(x, y, self.width, self.height, self.params.wrap, |index, share| {
+            let point = Point {
+                weight: point.weight * share,
+                ..point
+            };
+
+            match accumulation {
+                Accumulation::Dense => pixels[index].add(point, color_space),
+                Accumulation::Sparse {..} => points.push((index, point)),
+            }
+        });
     }
 }
 
+/// Derives the seed of the `index`-th worker from the global seed, by jumping ahead in a xoshiro stream
+fn worker_seed(seed: u64, index: usize) -> [u8; 32] {
+    let mut rng = rand_xoshiro::Xoshiro256Plus::seed_from_u64(seed);
+    for _ in 0..index {
+        rng.jump();
+    }
+
+    rng.gen()
+}
+
 impl<R: Rule> Clone for WorldParams<R> {
     fn clone(&self) -> Self {
         Self {
             zoom: self.zoom,
             center: self.center,
+            camera: self.camera,
+            wrap: self.wrap,
             rule: self.rule.clone(),
+            final_rule: self.final_rule.clone(),
             steps: self.steps,
             scatter_steps: self.scatter_steps,
             burnin_steps: self.burnin_steps,
             shape: self.shape.clone(),
             gain: self.gain,
+            tone_map: self.tone_map.clone(),
+            seed: self.seed,
+            max_steps: self.max_steps,
+            accumulation: self.accumulation,
+            splat: self.splat,
+            supersample: self.supersample,
+            density_estimation: self.density_estimation,
+            background: self.background,
+            color_space: self.color_space,
+            convergence: self.convergence,
         }
     }
 }
@@ -462,6 +999,24 @@ impl State {
         true
     }
 
+    /// Adds a batch sent by a worker to this state, whose points are given in `color_space`; returns false if its dimensions don't match
+    fn combine_batch(&mut self, batch: Batch, color_space: ColorSpace) -> bool {
+        match batch {
+            Batch::Dense(other) => self.combine(other),
+            Batch::Sparse {points, steps, width, height} => {
+                if width != self.width || height != self.height {
+                    return false
+                }
+
+                self.steps += steps;
+                for (index, point) in points {
+                    self.pixels[index].add(point, color_space);
+                }
+                true
+            }
+        }
+    }
+
     pub fn reset(&mut self, width: usize, height: usize) {
         if width * height == self.pixels.len() {
             for p in self.pixels.iter_mut() {
@@ -475,26 +1030,44 @@ impl State {
         self.steps = 0;
     }
 
-    /// Assumes the default texture format: `wgpu::TextureFormat::Rgba8UnormSrgb`
-    pub fn draw(&self, frame: &mut [u8], gain: f64) {
-        use std::ops::Neg;
+    /// Returns the gain to draw this state with, resolving `Gain::Auto` from the hit counts of its pixels
+    pub fn gain(&self, gain: Gain, tone_map: &ToneMap) -> f64 {
+        let (percentile, target) = match gain {
+            Gain::Fixed(gain) => return gain,
+            Gain::Auto {percentile, target} => (percentile, target),
+        };
+
+        let mut densities = self.pixels.iter().map(|p| p.n).filter(|n| *n > 0.0).collect::<Vec<_>>();
+        let x = tone_map.inverse(target);
 
-        let bg_r = (BG_R.powf(1.0 / GAMMA) * 255.0) as u8;
-        let bg_g = (BG_G.powf(1.0 / GAMMA) * 255.0) as u8;
-        let bg_b = (BG_B.powf(1.0 / GAMMA) * 255.0) as u8;
+        match x {
+            Some(x) if !densities.is_empty() && self.steps > 0 => {
+                let index = ((densities.len() - 1) as f64 * percentile).round() as usize;
+                let (_, n, _) = densities.select_nth_unstable_by(index, f64::total_cmp);
+
+                // Solves n * width * height / steps * gain = x
+                x * self.steps as f64 / (*n * self.width as f64 * self.height as f64)
+            }
+            // Either nothing is lit or the tone map ignores the gain
+            _ => 1.0,
+        }
+    }
+
+    /// Assumes the default texture format: `wgpu::TextureFormat::Rgba8UnormSrgb`;
+    /// the colors of the pixels were accumulated in `color_space`
+    pub fn draw(&self, frame: &mut [u8], gain: f64, tone_map: &ToneMap, background: Background, color_space: ColorSpace) {
+        let empty = background.empty();
 
         // Nothing to draw, simply fill the buffer with the background color
         if self.steps == 0 || self.pixels.len() * 4 != frame.len() {
             for pixel in frame.chunks_exact_mut(4) {
-                pixel[0] = bg_r;
-                pixel[1] = bg_g;
-                pixel[2] = bg_b;
-                pixel[3] = 255;
+                pixel.copy_from_slice(&empty);
             }
             return;
         }
 
         let ratio = self.width as f64 * self.height as f64 / self.steps as f64 * gain;
+        let alphas = tone_map.alphas(&self.pixels, ratio);
 
         // Draw all the pixels
         for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
@@ -503,21 +1076,32 @@ impl State {
             }
 
             let p = self.pixels[i];
-            let a = 1.0 - (p.n * ratio).neg().exp();
-            let r = ((p.r_sum / p.n * a + BG_R * (1.0 - a)).powf(1.0 / GAMMA) * 255.0) as u8;
-            let g = ((p.g_sum / p.n * a + BG_G * (1.0 - a)).powf(1.0 / GAMMA) * 255.0) as u8;
-            let b = ((p.b_sum / p.n * a + BG_B * (1.0 - a)).powf(1.0 / GAMMA) * 255.0) as u8;
 
             if p.n > 0.0 {
-                pixel[0] = r;
-                pixel[1] = g;
-                pixel[2] = b;
-                pixel[3] = 255;
+                let color = color_space.to_linear((p.r_sum / p.n, p.g_sum / p.n, p.b_sum / p.n));
+                pixel.copy_from_slice(&background.blend(color, alphas[i]));
             } else {
-                pixel[0] = bg_r;
-                pixel[1] = bg_g;
-                pixel[2] = bg_b;
-                pixel[3] = 255;
+                pixel.copy_from_slice(&empty);
+            }
+        }
+    }
+
+    /// Like `draw` over a transparent background, but writes the premultiplied linear RGBA of each pixel without quantizing it
+    pub fn draw_linear(&self, frame: &mut Vec<[f64; 4]>, gain: f64, tone_map: &ToneMap, color_space: ColorSpace) {
+        frame.clear();
+        frame.resize(self.pixels.len(), [0.0; 4]);
+        if self.steps == 0 {
+            return;
+        }
+
+        let ratio = self.width as f64 * self.height as f64 / self.steps as f64 * gain;
+        let alphas = tone_map.alphas(&self.pixels, ratio);
+
+        for ((target, p), a) in frame.iter_mut().zip(self.pixels.iter()).zip(alphas) {
+            if p.n > 0.0 {
+                let (r, g, b) = color_space.to_linear((p.r_sum / p.n, p.g_sum / p.n, p.b_sum / p.n));
+                let a = a.clamp(0.0, 1.0);
+                *target = [r.clamp(0.0, 1.0) * a, g.clamp(0.0, 1.0) * a, b.clamp(0.0, 1.0) * a, a];
             }
         }
     }
@@ -535,28 +1119,231 @@ impl State {
             0.0
         }
     }
+
+    /// Returns the root mean square of `Pixel::relative_error` over the lit pixels, weighted by their lightness
+    pub fn relative_noise(&self) -> f64 {
+        let mut error = 0.0;
+        let mut total = 0.0;
+
+        for pixel in self.pixels.iter().filter(|p| p.n > 0.0) {
+            let mean = pixel.mean_lightness();
+            let relative_error = pixel.relative_error();
+            error += mean * relative_error * relative_error;
+            total += mean;
+        }
+
+        if total > 0.0 {
+            (error / total).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    /// Draws the relative error of each pixel, from black for converged pixels to white for the noisiest ones, through red and yellow;
+    /// the errors are normalized by their 99th percentile over the lit pixels, and unlit pixels are left black
+    pub fn draw_noise(&self, frame: &mut [u8]) {
+        let mut errors = self.pixels.iter().filter(|p| p.n > 0.0).map(|p| p.relative_error()).collect::<Vec<_>>();
+        errors.sort_by(|a, b| a.total_cmp(b));
+        let max_error = errors.get(errors.len() * 99 / 100).copied().unwrap_or(1.0).max(f64::EPSILON);
+
+        for (pixel, p) in frame.chunks_exact_mut(4).zip(self.pixels.iter()) {
+            let error = if p.n > 0.0 {
+                p.relative_error() / max_error
+            } else {
+                0.0
+            };
+            let ramp = |offset: f64| ((3.0 * error - offset).clamp(0.0, 1.0) * 255.0).round() as u8;
+
+            pixel.copy_from_slice(&[ramp(0.0), ramp(1.0), ramp(2.0), 255]);
+        }
+    }
+
+    /// Returns a copy of this state whose sums were accumulated in `color_space`, converted to linear sRGB:
+    /// the average color of each pixel is converted, and then multiplied back by its weight
+    pub fn to_linear(&self, color_space: ColorSpace) -> State {
+        let mut res = self.clone();
+
+        if color_space != ColorSpace::Linear {
+            for pixel in res.pixels.iter_mut().filter(|p| p.n > 0.0) {
+                let (r, g, b) = color_space.to_linear((pixel.r_sum / pixel.n, pixel.g_sum / pixel.n, pixel.b_sum / pixel.n));
+                pixel.r_sum = r * pixel.n;
+                pixel.g_sum = g * pixel.n;
+                pixel.b_sum = b * pixel.n;
+            }
+        }
+
+        res
+    }
 }
 
 impl Image {
-    pub fn empty(width: usize, height: usize) -> Self {
-        let bg_r = (BG_R.powf(1.0 / GAMMA) * 255.0) as u8;
-        let bg_g = (BG_G.powf(1.0 / GAMMA) * 255.0) as u8;
-        let bg_b = (BG_B.powf(1.0 / GAMMA) * 255.0) as u8;
-
+    pub fn empty(width: usize, height: usize, background: Background) -> Self {
+        let empty = background.empty();
         let mut res = vec![0u8; width * height * 4];
 
         for pixel in res.chunks_exact_mut(4) {
-            pixel[0] = bg_r;
-            pixel[1] = bg_g;
-            pixel[2] = bg_b;
-            pixel[3] = 255;
+            pixel.copy_from_slice(&empty);
         }
 
         Self {
             pixels: res,
+            linear: Vec::new(),
             steps: 0,
             width,
-            height
+            height,
+            noise: None,
+            converged: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Moves the points by a fixed offset
+    #[derive(Clone)]
+    struct TranslateRule(f64, f64);
+
+    impl Rule for TranslateRule {
+        fn next(&mut self, previous: Point, history: &[usize], _shape: &Shape, _scatter: bool) -> (Point, usize) {
+            (Point {x: previous.x + self.0, y: previous.y + self.1, ..previous}, history[0])
+        }
+
+        fn reseed(&mut self, _seed: &[u8; 32]) {}
+    }
+
+    fn render(accumulation: Accumulation, final_rule: Option<BoxedRule>) -> State {
+        let max_steps = 60_000;
+
+        RuleRng::reset_instance_seeds();
+        let params = WorldParams {
+            zoom: 1.0,
+            center: (0.0, 0.0),
+            camera: Camera::default(),
+            wrap: false,
+            rule: RuleBox::new(BoxedRule::new(DefaultRule::default())),
+            final_rule: final_rule.map(RuleBox::new),
+            steps: 1000,
+            scatter_steps: 2,
+            burnin_steps: 10,
+            shape: polygon(5),
+            gain: Gain::default(),
+            tone_map: ToneMap::default(),
+            seed: Some(5),
+            max_steps: Some(max_steps),
+            accumulation,
+            splat: Splat::default(),
+            supersample: Supersample::default(),
+            density_estimation: None,
+            background: Background::default(),
+            color_space: ColorSpace::default(),
+            convergence: None,
+        };
+
+        // A short queue makes the workers wait on each other, shuffling the order in which their batches arrive
+        let mut world = World::new(32, 24, params, 4, 1);
+        while world.steps() < max_steps {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        world.stop();
+
+        world.accumulation().unwrap().clone()
+    }
+
+    #[test]
+    fn test_lightness_color_space() {
+        let linear = Point::new(0.0, 0.0, (0.8, 0.1, 0.3));
+        let mut oklab = linear;
+        oklab.set_color(ColorSpace::Oklab.from_linear(linear.color()));
+
+        let expected = 0.2126 * 0.8 + 0.7152 * 0.1 + 0.0722 * 0.3;
+        assert!((linear.lightness(ColorSpace::Linear) - expected).abs() < 1e-12);
+        assert!((oklab.lightness(ColorSpace::Oklab) - expected).abs() < 1e-6);
+
+        // The lightness sums, which the noise and the L/L2 channels are computed from, don't depend on the working space
+        #[cfg(feature = "sigma")]
+        if true {
+            let mut a = Pixel::default();
+            let mut b = Pixel::default();
+            a.add(linear, ColorSpace::Linear);
+            b.add(oklab, ColorSpace::Oklab);
+            assert!((a.l_sum - b.l_sum).abs() < 1e-6);
+            assert!((a.l_squared - b.l_squared).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_seeded_render_is_reproducible() {
+        for accumulation in [Accumulation::Dense, Accumulation::Sparse {batch_size: 5000}] {
+            let a = render(accumulation, None);
+            let b = render(accumulation, None);
+
+            assert_eq!(a.steps, b.steps);
+            for (a, b) in a.pixels.iter().zip(b.pixels.iter()) {
+                assert_same_pixel(a, b);
+            }
+        }
+    }
+
+    fn assert_same_pixel(a: &Pixel, b: &Pixel) {
+        assert_eq!(a.n.to_bits(), b.n.to_bits());
+        assert_eq!([a.r_sum, a.g_sum, a.b_sum].map(f64::to_bits), [b.r_sum, b.g_sum, b.b_sum].map(f64::to_bits));
+    }
+
+    #[test]
+    fn test_final_rule_is_not_fed_back() {
+        let plain = render(Accumulation::Dense, None);
+
+        let identity = render(Accumulation::Dense, Some(BoxedRule::new(IdentityRule)));
+        for (a, b) in plain.pixels.iter().zip(identity.pixels.iter()) {
+            assert_same_pixel(a, b);
+        }
+
+        // At this zoom, a unit is 12 pixels: if the offset was fed back into the orbit, the attractor would be distorted instead of moved
+        let shifted = render(Accumulation::Dense, Some(BoxedRule::new(TranslateRule(0.5, 0.0))));
+        assert_eq!(plain.steps, shifted.steps);
+        for y in 0..plain.height {
+            for x in 6..plain.width {
+                assert_same_pixel(&plain.pixels[y * plain.width + x - 6], &shifted.pixels[y * plain.width + x]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise() {
+        // Gray points, whose lightness is their value
+        let pixel = |values: &[f64]| {
+            let mut pixel = Pixel::default();
+            for value in values {
+                pixel.add(Point::new(0.0, 0.0, (*value, *value, *value)), ColorSpace::Linear);
+            }
+            pixel
+        };
+        let state = State::new(vec![
+            Pixel::default(),
+            pixel(&[0.5]),
+            pixel(&[0.0, 1.0, 1.0, 1.0]),
+            pixel(&[0.5; 4]),
+        ], 9, 2, 2);
+
+        let mut frame = vec![0; 16];
+        state.draw_noise(&mut frame);
+
+        if cfg!(feature = "sigma") {
+            // The relative errors of the lit pixels are 1, 1/√12 and 0; the noise is weighted by their mean lightness
+            let error = 1.0 / 12.0f64.sqrt();
+            let expected = ((0.5 + 0.75 * error * error) / (0.5 + 0.75 + 0.5)).sqrt();
+            assert!((state.relative_noise() - expected).abs() < 1e-9);
+
+            let red = (3.0 * error * 255.0).round() as u8;
+            assert_eq!(frame, [0, 0, 0, 255, 255, 255, 255, 255, red, 0, 0, 255, 0, 0, 0, 255]);
+        } else {
+            // Without the lightness sums, only the pixels hit less than twice are noisy
+            assert_eq!(state.relative_noise(), 0.0);
+            assert_eq!(frame, [0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
         }
+
+        assert_eq!(State::new(vec![Pixel::default(); 4], 0, 2, 2).relative_noise(), 0.0);
     }
 }